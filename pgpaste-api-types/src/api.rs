@@ -5,7 +5,7 @@ use std::time::Duration;
 use mime::Mime;
 use serde::{Deserialize, Serialize};
 
-use crate::{Paste, SystemTime, Visibility};
+use crate::{InboxEntry, Paste, Revision, SystemTime, Visibility};
 
 /// `MsgPack` Body payload for POST `/api/paste` endpoint
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -24,6 +24,15 @@ pub struct CreateBody {
 	pub burn_after_read: bool,
 	/// The inner OpenPGP message
 	pub message: Vec<u8>,
+
+	// `MsgPack` structs are positional, the fields below are last and defaulted
+	// since older clients do not send them
+	/// Slug of the paste this one replies to
+	#[serde(default)]
+	pub parent: Option<String>,
+	/// The absolute time at which the paste should be deleted, exclusive with `burn_in`
+	#[serde(default)]
+	pub burn_at: Option<SystemTime>,
 }
 
 /// `MsgPack` Body response for POST `/api/paste` endpoint
@@ -38,5 +47,111 @@ pub struct CreateResponse {
 
 /// `MsgPack` Body payload for GET `/api/paste` endpoint
 pub type ReadBody = ();
+/// Query parameters for GET `/api/paste` endpoint
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReadQuery {
+	/// The revision of the paste to read, defaults to the latest one
+	pub rev: Option<u32>,
+}
 /// `MsgPack` Body response for GET `/api/paste` endpoint
 pub type ReadResponse = Paste;
+
+/// `MsgPack` Body response for GET `/api/paste/:slug/revisions` endpoint
+pub type RevisionsResponse = Vec<Revision>;
+
+/// `MsgPack` Body payload for GET `/api/inbox` endpoint, signed by the caller
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InboxBody {
+	/// The time at which the request was signed, old requests are refused
+	pub issued_at: SystemTime,
+}
+
+/// `MsgPack` Body response for GET `/api/inbox` endpoint
+pub type InboxResponse = Vec<InboxEntry>;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A create body as sent by clients without reply links nor absolute burn dates
+	#[derive(Serialize)]
+	struct BaselineCreateBody {
+		/// See [`CreateBody::slug`]
+		slug: Option<String>,
+		/// See [`CreateBody::mime`]
+		#[serde(with = "crate::mime_proxy")]
+		mime: Mime,
+		/// See [`CreateBody::visibility`]
+		visibility: Visibility,
+		/// See [`CreateBody::burn_in`]
+		burn_in: Option<Duration>,
+		/// See [`CreateBody::burn_after_read`]
+		burn_after_read: bool,
+		/// See [`CreateBody::message`]
+		message: Vec<u8>,
+	}
+
+	#[test]
+	fn baseline_create_body_decodes() -> Result<(), rmp_serde::decode::Error> {
+		let old = BaselineCreateBody {
+			slug: Some("slug".into()),
+			mime: mime::TEXT_PLAIN,
+			visibility: Visibility::Public,
+			burn_in: Some(Duration::from_secs(60)),
+			burn_after_read: true,
+			message: vec![1, 2, 3],
+		};
+		let bytes = rmp_serde::to_vec(&old).expect("the body encodes");
+
+		let body: CreateBody = rmp_serde::from_slice(&bytes)?;
+		assert_eq!(body.slug, old.slug);
+		assert_eq!(body.mime, old.mime);
+		assert_eq!(body.visibility, old.visibility);
+		assert_eq!(body.burn_in, old.burn_in);
+		assert_eq!(body.parent, None);
+		assert_eq!(body.burn_at, None);
+		assert!(body.burn_after_read);
+		assert_eq!(body.message, old.message);
+
+		Ok(())
+	}
+
+	/// A paste as sent by servers without reply links nor burn after read
+	#[derive(Serialize)]
+	struct BaselinePaste {
+		/// See [`Paste::slug`]
+		slug: String,
+		/// See [`Paste::mime`]
+		#[serde(with = "crate::mime_proxy")]
+		mime: Mime,
+		/// See [`Paste::visibility`]
+		visibility: Visibility,
+		/// See [`Paste::burn_at`]
+		burn_at: SystemTime,
+		/// See [`Paste::inner`]
+		inner: Vec<u8>,
+	}
+
+	#[test]
+	fn read_response_without_links_decodes() -> Result<(), rmp_serde::decode::Error> {
+		let old = BaselinePaste {
+			slug: "slug".into(),
+			mime: mime::TEXT_PLAIN,
+			visibility: Visibility::Public,
+			burn_at: SystemTime::UNIX_EPOCH,
+			inner: vec![1, 2, 3],
+		};
+		let bytes = rmp_serde::to_vec(&old).expect("the paste encodes");
+
+		let paste: ReadResponse = rmp_serde::from_slice(&bytes)?;
+		assert_eq!(paste.inner, old.inner);
+		assert_eq!(paste.signer, "");
+		assert_eq!(paste.parent, None);
+		assert!(paste.children.is_empty());
+		assert!(!paste.burn_after_read);
+
+		Ok(())
+	}
+}
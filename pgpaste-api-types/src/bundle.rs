@@ -0,0 +1,181 @@
+//! Multi-file paste bundles
+//!
+//! A bundle is a paste whose inner literal data starts with a `MsgPack`
+//! encoded [`Manifest`], followed by the content of each file in the order
+//! of the manifest entries. Bundles are marked with the [`BUNDLE_MIME`] type,
+//! whatever the paste visibility is.
+
+use std::io::Cursor;
+
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+
+/// Mime type of pastes that contain a bundle of files
+pub const BUNDLE_MIME: &str = "application/vnd.pgpaste.bundle+msgpack";
+
+/// Whether the given mime type marks a bundle paste
+#[must_use]
+pub fn is_bundle(mime: &Mime) -> bool {
+	mime.essence_str() == BUNDLE_MIME
+}
+
+/// Description of the files contained in a bundle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+	/// The bundle files, in the order their content is stored
+	pub entries: Vec<Entry>,
+}
+
+/// A single file of a bundle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+	/// File name, without any directory component
+	pub name: String,
+	/// File mime type
+	#[serde(with = "crate::mime_proxy")]
+	pub mime: Mime,
+	/// File size in bytes
+	pub size: u64,
+}
+
+/// Error returned when a bundle cannot be decoded
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+	/// The manifest could not be encoded
+	#[error("invalid bundle manifest: {0}")]
+	Encode(#[from] rmp_serde::encode::Error),
+	/// The manifest could not be decoded
+	#[error("invalid bundle manifest: {0}")]
+	Decode(#[from] rmp_serde::decode::Error),
+	/// The files content does not match the sizes announced in the manifest
+	#[error("bundle content does not match its manifest")]
+	SizeMismatch,
+}
+
+/// Encode the given files into bundle literal data
+///
+/// # Errors
+/// Fails if the manifest cannot be serialized
+pub fn encode<'a, I>(files: I) -> Result<Vec<u8>, BundleError>
+where
+	I: IntoIterator<Item = (String, Mime, &'a [u8])>,
+{
+	let (entries, contents): (Vec<_>, Vec<_>) = files
+		.into_iter()
+		.map(|(name, mime, content)| {
+			let size = content.len() as u64;
+			(Entry { name, mime, size }, content)
+		})
+		.unzip();
+
+	let mut data = rmp_serde::to_vec(&Manifest { entries })?;
+	for content in contents {
+		data.extend_from_slice(content);
+	}
+
+	Ok(data)
+}
+
+/// Decode bundle literal data into its manifest and the content of each entry
+///
+/// # Errors
+/// Fails if the manifest is invalid or does not match the files content
+pub fn decode(data: &[u8]) -> Result<(Manifest, Vec<&[u8]>), BundleError> {
+	let mut cursor = Cursor::new(data);
+	let manifest: Manifest = rmp_serde::from_read(&mut cursor)?;
+
+	let mut rest = usize::try_from(cursor.position())
+		.ok()
+		.and_then(|offset| data.get(offset..))
+		.ok_or(BundleError::SizeMismatch)?;
+
+	let mut contents = Vec::with_capacity(manifest.entries.len());
+	for entry in &manifest.entries {
+		let size = usize::try_from(entry.size).map_err(|_| BundleError::SizeMismatch)?;
+		if size > rest.len() {
+			return Err(BundleError::SizeMismatch);
+		}
+
+		let (content, tail) = rest.split_at(size);
+		contents.push(content);
+		rest = tail;
+	}
+
+	if !rest.is_empty() {
+		return Err(BundleError::SizeMismatch);
+	}
+
+	Ok((manifest, contents))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Encodes a bundle of a log, an empty file and a patch
+	fn files() -> Result<Vec<u8>, BundleError> {
+		encode([
+			(
+				"app.log".into(),
+				mime::TEXT_PLAIN,
+				&b"started\nfailed\n"[..],
+			),
+			("empty.txt".into(), mime::TEXT_PLAIN, &b""[..]),
+			(
+				"fix.patch".into(),
+				"text/x-diff".parse().expect("valid mime"),
+				&b"+fix\n"[..],
+			),
+		])
+	}
+
+	#[test]
+	fn round_trip() -> Result<(), BundleError> {
+		let data = files()?;
+		let (manifest, contents) = decode(&data)?;
+
+		let names = manifest
+			.entries
+			.iter()
+			.map(|e| e.name.as_str())
+			.collect::<Vec<_>>();
+		assert_eq!(names, ["app.log", "empty.txt", "fix.patch"]);
+		assert_eq!(manifest.entries[2].mime.essence_str(), "text/x-diff");
+		assert_eq!(contents, [&b"started\nfailed\n"[..], b"", b"+fix\n"]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn size_larger_than_remaining_bytes() -> Result<(), BundleError> {
+		let mut data = files()?;
+		data.pop();
+
+		assert!(matches!(decode(&data), Err(BundleError::SizeMismatch)));
+		Ok(())
+	}
+
+	#[test]
+	fn trailing_bytes() -> Result<(), BundleError> {
+		let mut data = files()?;
+		data.push(b'!');
+
+		assert!(matches!(decode(&data), Err(BundleError::SizeMismatch)));
+		Ok(())
+	}
+
+	#[test]
+	fn truncated_manifest() -> Result<(), BundleError> {
+		let manifest = rmp_serde::to_vec(&Manifest {
+			entries: vec![Entry {
+				name: "app.log".into(),
+				mime: mime::TEXT_PLAIN,
+				size: 0,
+			}],
+		})?;
+
+		let truncated = &manifest[..manifest.len() / 2];
+		assert!(matches!(decode(truncated), Err(BundleError::Decode(_))));
+		Ok(())
+	}
+}
@@ -8,6 +8,7 @@ use mime::Mime;
 use serde::{Deserialize, Serialize};
 
 pub mod api;
+pub mod bundle;
 
 /// The visibility of a paste
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -35,6 +36,51 @@ pub struct Paste {
 	pub burn_at: SystemTime,
 	/// The inner OpenPGP message
 	pub inner: Vec<u8>,
+
+	// `MsgPack` structs are positional, the fields below are last and defaulted
+	// since older servers do not send them
+	/// Fingerprint of the key that created the paste
+	#[serde(default)]
+	pub signer: String,
+	/// Slug of the paste this one replies to
+	#[serde(default)]
+	pub parent: Option<String>,
+	/// Slugs of the pastes that reply to this one
+	#[serde(default)]
+	pub children: Vec<String>,
+	/// Whether the paste is deleted after being read
+	#[serde(default)]
+	pub burn_after_read: bool,
+}
+
+/// Metadata about a revision of a paste
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revision {
+	/// Revision number, starting at 1 for the first version of the paste
+	pub revision: u32,
+	/// Content mime type
+	#[serde(with = "mime_proxy")]
+	pub mime: Mime,
+	/// The paste visibility
+	pub visibility: Visibility,
+	/// The time at which the revision was created
+	pub created_at: SystemTime,
+}
+
+/// A private paste listed in the inbox of one of its recipients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InboxEntry {
+	/// Paste slug
+	pub slug: String,
+	/// Content mime type
+	#[serde(with = "mime_proxy")]
+	pub mime: Mime,
+	/// Fingerprint of the key that created the paste
+	pub signer: String,
+	/// The time at which the paste was created
+	pub created_at: SystemTime,
+	/// The time at which the paste will be deleted
+	pub burn_at: SystemTime,
 }
 
 /// Proxy ser/deserialization module for `mime` crate
@@ -1,17 +1,24 @@
 //! CLI definition
 
 use std::{
+	env::{self, VarError},
+	fs,
 	io::{IsTerminal, Read, stdin, stdout},
-	path::PathBuf,
-	time::Duration,
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+	time::{Duration, SystemTime},
 };
 
-use clap::{Args, Parser, Subcommand, value_parser};
+use clap::{Args, Parser, Subcommand, builder::BoolishValueParser, value_parser};
 use clap_complete::Shell;
 use duration_human::DurationHuman;
+use eyre::{Context, ContextCompat};
 use mime::Mime;
-use pgpaste_api_types::Visibility;
-use sequoia_openpgp::{KeyHandle, crypto::Password};
+use pgpaste_api_types::{Visibility, bundle};
+use sequoia_openpgp::{Fingerprint, KeyHandle, crypto::Password};
+use zeroize::Zeroizing;
+
+use crate::{guess::guess_mime, output::Format};
 
 /// A `PasteBin` like service that lays on encryption
 #[derive(Debug, Parser)]
@@ -30,10 +37,26 @@ pub(crate) struct PGPasteArgs {
 	#[clap(long)]
 	pub(crate) config: Option<PathBuf>,
 
+	/// The config profile to use, `--server` still overrides its server
+	#[clap(long, global = true, env = "PGPASTE_PROFILE")]
+	pub(crate) profile: Option<String>,
+
 	/// Whether the output should be less verbose
 	#[clap(long)]
 	pub(crate) quiet: bool,
 
+	/// How results are printed on the standard output
+	#[clap(long, global = true, value_enum, default_value_t)]
+	pub(crate) format: Format,
+
+	/// Fail instead of prompting for passwords or missing information
+	#[clap(long, global = true, env = "PGPASTE_NO_INTERACTIVE", value_parser = BoolishValueParser::new())]
+	pub(crate) no_interactive: bool,
+
+	/// Only use the cert cache, never look certs up on the network
+	#[clap(long, global = true, env = "PGPASTE_OFFLINE", value_parser = BoolishValueParser::new())]
+	pub(crate) offline: bool,
+
 	/// Generate completion script for the given shell
 	#[clap(long, value_parser = value_parser!(Shell))]
 	pub(crate) generate: Option<Shell>,
@@ -46,6 +69,21 @@ pub(crate) enum Commands {
 
 	/// Read an existing paste
 	Read(ReadArgs),
+
+	/// Edit one of your pastes in `$EDITOR` and overwrite it
+	Edit(EditArgs),
+
+	/// List the private pastes addressed to your key
+	Inbox(InboxArgs),
+
+	/// Set up a key pair and the config file
+	Init(InitArgs),
+
+	/// Manage the keys known to the CLI
+	Key(KeyArgs),
+
+	/// Run a daemon keeping unlocked keys for the next invocations
+	Agent(AgentArgs),
 }
 
 /// Arguments to create a new paste
@@ -56,13 +94,17 @@ pub(crate) struct CreateArgs {
 	#[clap(long, short)]
 	pub(crate) slug: Option<String>,
 
+	/// The slug of the paste this one replies to
+	#[clap(long)]
+	pub(crate) reply_to: Option<String>,
+
 	/// The content of the paste
 	#[clap(long, short, group = "message_content")]
 	content: Option<String>,
 
-	/// The file to read the content from
-	#[clap(long, group = "message_content")]
-	file: Option<PathBuf>,
+	/// The file to read the content from, repeat it to create a bundle
+	#[clap(long = "file", group = "message_content")]
+	files: Vec<PathBuf>,
 
 	/// Whether private and protected pastes should be signed
 	#[clap(long)]
@@ -73,46 +115,62 @@ pub(crate) struct CreateArgs {
 	#[clap(long)]
 	pub(crate) dump_message: Option<PathBuf>,
 
-	// TODO: guess mime type
-	/// The mime type of the content
+	/// The mime type of the content, guessed from the file and the content if unset
 	#[clap(long, value_parser = parsers::to_mime)]
 	pub(crate) mime: Option<Mime>,
 
-	/// The visibility of the paste
+	/// The visibility of the paste, the profile default visibility if unset
 	#[clap(long, short, value_parser = parsers::to_api_visibility)]
-	pub(crate) mode: Visibility,
+	pub(crate) mode: Option<Visibility>,
 
 	/// The longevity of the paste
 	#[clap(long, group = "time", value_parser = parsers::to_duration_human)]
 	lifetime: Option<DurationHuman>,
-	/// When should the paste be burned
-	#[clap(long, group = "time", value_parser = parsers::to_do::<Option<String>>)]
-	burn_date: Option<String>,
+	/// When should the paste be burned, either an RFC 3339 date, a local
+	/// `YYYY-MM-DD [HH:MM[:SS]]` date, `tomorrow` or `in <duration>`
+	#[clap(long, group = "time", value_parser = parsers::to_system_time)]
+	burn_date: Option<SystemTime>,
 	/// Whether the paste should be burned after reading
 	#[clap(long)]
 	pub(crate) burn_after_read: bool,
 
-	/// The recipient of the paste in case of a private paste
-	#[clap(long, value_parser = parsers::to_key_handle)]
-	pub(crate) recipient: Option<KeyHandle>,
+	/// The recipients of the paste in case of a private paste, can be repeated
+	#[clap(long = "recipient", value_parser = parsers::to_key_handle)]
+	pub(crate) recipients: Vec<KeyHandle>,
 
-	// TODO: implement obscuring recipients
-	/// Whether the recipient(s) should be public in the encryption process
-	/// using wildcard key
+	/// Whether the recipient(s) should be hidden in the encryption process
+	/// using wildcard key IDs
 	#[clap(long)]
 	pub(crate) obscure: bool,
 
 	/// Overwrite an existing paste with the same slug and from the same account
 	#[clap(long)]
 	pub(crate) overwrite: bool,
+
+	/// Where to get the password of a protected paste
+	#[clap(flatten)]
+	pub(crate) password: PasswordArgs,
+
+	/// Put the password of a protected paste in the fragment of the printed URL,
+	/// browsers never send it to the server
+	#[clap(long)]
+	pub(crate) share_password: bool,
 }
 
 impl CreateArgs {
-	/// Get the content of the paste from different sources
-	pub(crate) fn content(&self) -> eyre::Result<Vec<u8>> {
+	/// Get the content of the paste and its mime type from different sources
+	pub(crate) fn content(&self) -> eyre::Result<(Vec<u8>, Mime)> {
+		if let [_, _, ..] = self.files.as_slice() {
+			if self.mime.is_some() {
+				eyre::bail!("`--mime` cannot be used when bundling multiple files");
+			}
+
+			return Ok((self.bundle()?, bundle::BUNDLE_MIME.parse()?));
+		}
+
 		let content = if let Some(content) = &self.content {
 			content.as_bytes().to_owned()
-		} else if let Some(file) = &self.file {
+		} else if let Some(file) = self.files.first() {
 			std::fs::read(file)?
 		} else if stdout().is_terminal() {
 			let mut vec = Vec::new();
@@ -122,39 +180,315 @@ impl CreateArgs {
 			eyre::bail!("I could not get paste content by a `--file`, a `--content` or stdin.")
 		};
 
-		Ok(content)
+		let mime = match &self.mime {
+			Some(mime) => mime.clone(),
+			None => guess_mime(self.files.first().map(PathBuf::as_path), &content),
+		};
+
+		Ok((content, mime))
+	}
+
+	/// Pack all the given files in a bundle
+	fn bundle(&self) -> eyre::Result<Vec<u8>> {
+		let mut files = Vec::with_capacity(self.files.len());
+
+		for path in &self.files {
+			let name = path
+				.file_name()
+				.and_then(|name| name.to_str())
+				.wrap_err_with(|| format!("`{}` has no valid file name", path.display()))?
+				.to_owned();
+
+			if files.iter().any(|(other, _)| other == &name) {
+				eyre::bail!("multiple files are named `{name}`");
+			}
+
+			files.push((name, std::fs::read(path)?));
+		}
+
+		let bundle = bundle::encode(files.iter().map(|(name, content)| {
+			let mime = guess_mime(Some(Path::new(name)), content);
+			log::info!("Bundling `{name}` as {mime}");
+
+			(name.clone(), mime, content.as_slice())
+		}))?;
+
+		Ok(bundle)
 	}
 
 	/// Get the lifetime of the paste
-	#[allow(clippy::unnecessary_wraps)]
-	pub(crate) fn burn_in(&self) -> eyre::Result<Option<Duration>> {
-		let dur: Option<Duration> = self.lifetime.as_ref().map(Into::into);
+	pub(crate) fn burn_in(&self) -> Option<Duration> {
+		self.lifetime.as_ref().map(Into::into)
+	}
 
-		// TODO: implement and handle `burn_date`
-		// see if SystemTime is the right type
+	/// Get the absolute burn date of the paste
+	pub(crate) fn burn_at(&self) -> eyre::Result<Option<SystemTime>> {
+		if let Some(burn_date) = self.burn_date
+			&& burn_date <= SystemTime::now()
+		{
+			eyre::bail!("the burn date is in the past");
+		}
 
-		Ok(dur)
+		Ok(self.burn_date)
 	}
 }
 
 /// Arguments to read an existing paste
 #[derive(Debug, Args)]
 pub(crate) struct ReadArgs {
-	/// The slug of the paste to read
+	/// The slug of the paste to read or its full URL, which overrides the
+	/// server and may hold the password in its fragment
 	#[clap(long, short)]
 	pub(crate) slug: String,
 
-	/// The password to decrypt the paste
+	/// The revision of the paste to read, defaults to the latest one
+	#[clap(long)]
+	pub(crate) rev: Option<u32>,
+
+	/// Where to get the password of a protected paste
+	#[clap(flatten)]
+	pub(crate) password: PasswordArgs,
+
+	/// Extract the files of a bundle paste in the given directory
+	#[clap(long, conflicts_with = "output")]
+	pub(crate) extract: Option<PathBuf>,
+
+	/// Write the paste content to the given file instead of the standard output
+	#[clap(long, short)]
+	pub(crate) output: Option<PathBuf>,
+
+	/// Write binary content to the terminal anyway
+	#[clap(long)]
+	pub(crate) force: bool,
+
+	/// Fail if the content of the paste carries no good signature
+	#[clap(long)]
+	pub(crate) require_signed: bool,
+
+	/// Fail unless the content of the paste is signed by this cert, implies
+	/// `--require-signed`
+	#[clap(long, value_parser = parsers::to_fingerprint)]
+	pub(crate) expect_signer: Option<Fingerprint>,
+}
+
+/// Arguments to edit an existing paste
+#[derive(Debug, Args)]
+pub(crate) struct EditArgs {
+	/// The slug of the paste to edit or its full URL
+	pub(crate) slug: String,
+
+	/// Sign the edited private or protected paste even if it was not signed, signed
+	/// pastes are always signed again
+	#[clap(long)]
+	pub(crate) sign_private: bool,
+
+	/// Where to get the password of a protected paste
+	#[clap(flatten)]
+	pub(crate) password: PasswordArgs,
+}
+
+/// Sources of a paste password, it is prompted for when none is given
+#[derive(Debug, Args)]
+#[group(multiple = false)]
+pub(crate) struct PasswordArgs {
+	/// The password of the paste, `PGPASTE_PASSWORD` is used when no source is given
+	// Not a clap `env`, it would conflict with the other sources of the group
+	#[clap(long, short)]
+	password: Option<String>,
+
+	/// Read the password from a file, without its trailing newline
+	#[clap(long)]
+	password_file: Option<PathBuf>,
+
+	/// Read the password from an open file descriptor, without its trailing newline
+	#[clap(long)]
+	password_fd: Option<u32>,
+
+	/// Use the first line printed by a shell command as the password, e.g. `pass show pgpaste`
+	#[clap(long)]
+	password_command: Option<String>,
+}
+
+impl PasswordArgs {
+	/// Get the password from the given source, or from `PGPASTE_PASSWORD` if any
+	pub(crate) fn password(&self) -> eyre::Result<Option<Password>> {
+		let password = if let Some(password) = &self.password {
+			Zeroizing::new(password.clone())
+		} else if let Some(path) = &self.password_file {
+			read_secret(path)
+				.wrap_err_with(|| format!("could not read password from `{}`", path.display()))?
+		} else if let Some(fd) = self.password_fd {
+			read_secret(Path::new(&format!("/dev/fd/{fd}")))
+				.wrap_err_with(|| format!("could not read password from file descriptor {fd}"))?
+		} else if let Some(command) = &self.password_command {
+			run_secret_command(command)?
+		} else {
+			match env::var("PGPASTE_PASSWORD") {
+				Ok(password) => Zeroizing::new(password),
+				Err(VarError::NotPresent) => return Ok(None),
+				Err(err) => return Err(err).wrap_err("could not read `PGPASTE_PASSWORD`"),
+			}
+		};
+
+		Ok(Some(password.as_str().into()))
+	}
+}
+
+/// Read a secret from a file, dropping the trailing newline
+fn read_secret(path: &Path) -> eyre::Result<Zeroizing<String>> {
+	let mut secret = Zeroizing::new(fs::read_to_string(path)?);
+
+	let len = secret.trim_end_matches(['\n', '\r']).len();
+	secret.truncate(len);
+
+	Ok(secret)
+}
+
+/// Run a shell command and return the first line of its output
+fn run_secret_command(command: &str) -> eyre::Result<Zeroizing<String>> {
+	let output = Command::new("sh")
+		.args(["-c", command])
+		.stdin(Stdio::null())
+		.stderr(Stdio::inherit())
+		.output()
+		.wrap_err_with(|| format!("could not run `{command}`"))?;
+	let stdout = Zeroizing::new(output.stdout);
+
+	if !output.status.success() {
+		eyre::bail!("`{command}` failed with {}", output.status);
+	}
+
+	let stdout = str::from_utf8(&stdout).wrap_err("password is not valid UTF-8")?;
+
+	Ok(Zeroizing::new(
+		stdout.lines().next().unwrap_or_default().to_owned(),
+	))
+}
+
+/// Arguments to list the pastes addressed to your key
+#[derive(Debug, Args)]
+pub(crate) struct InboxArgs {
+	/// Also decrypt and show the content of each paste
+	#[clap(long)]
+	pub(crate) read: bool,
+}
+
+/// Arguments to set up the CLI
+#[derive(Debug, Args)]
+pub(crate) struct InitArgs {
+	/// Use an existing certificate with secret keys instead of generating one
+	#[clap(long)]
+	pub(crate) import: Option<PathBuf>,
+
+	/// The name of the generated key user ID
+	#[clap(long, conflicts_with = "import")]
+	pub(crate) name: Option<String>,
+	/// The email of the generated key user ID
+	#[clap(long, conflicts_with = "import")]
+	pub(crate) email: Option<String>,
+
+	/// Register the certificate with the server
+	#[clap(long)]
+	pub(crate) register: bool,
+
+	/// Replace the default key of an existing config
+	#[clap(long)]
+	pub(crate) force: bool,
+}
+
+/// Arguments to manage the keys known to the CLI
+#[derive(Debug, Args)]
+pub(crate) struct KeyArgs {
+	/// Which key command to execute
+	#[clap(subcommand)]
+	pub(crate) command: KeyCommands,
+}
+
+/// Key management commands
+#[derive(Debug, Subcommand)]
+pub(crate) enum KeyCommands {
+	/// Show the loaded keys, their capabilities and expiry
+	List,
+
+	/// Import certificates from a file, a fingerprint or an email address on the keyservers
+	Import(KeyImportArgs),
+
+	/// Export a certificate in its armored form
+	Export(KeyExportArgs),
+
+	/// Choose the default key used to sign and decrypt pastes
+	SetDefault(KeySetDefaultArgs),
+
+	/// Update the stored public certificates from the keyservers and WKD
+	Refresh,
+}
+
+/// Arguments to import certificates
+#[derive(Debug, Args)]
+pub(crate) struct KeyImportArgs {
+	/// A certificate file, a fingerprint or an email address to look up
+	pub(crate) source: String,
+}
+
+/// Arguments to export a certificate
+#[derive(Debug, Args)]
+pub(crate) struct KeyExportArgs {
+	/// The key to export
+	#[clap(value_parser = parsers::to_key_handle)]
+	pub(crate) key: KeyHandle,
+
+	/// Also export the secret keys
+	#[clap(long)]
+	pub(crate) secret: bool,
+
+	/// Write the certificate to a file instead of the standard output
 	#[clap(long, short)]
-	pub(crate) password: Option<Password>,
+	pub(crate) output: Option<PathBuf>,
+}
+
+/// Arguments to change the default key
+#[derive(Debug, Args)]
+pub(crate) struct KeySetDefaultArgs {
+	/// The key to use by default, it must be one of the private keys
+	#[clap(value_parser = parsers::to_key_handle)]
+	pub(crate) key: KeyHandle,
+}
+
+/// Arguments to run or control the agent daemon
+#[derive(Debug, Args)]
+pub(crate) struct AgentArgs {
+	/// Which agent command to execute, runs the agent if unset
+	#[clap(subcommand)]
+	pub(crate) command: Option<AgentCommands>,
+
+	/// How long the agent keeps a key after it was unlocked
+	#[clap(long, default_value = "10min", value_parser = parsers::to_duration_human)]
+	ttl: DurationHuman,
+}
+
+impl AgentArgs {
+	/// Get how long the agent keeps a key
+	pub(crate) fn ttl(&self) -> Duration {
+		(&self.ttl).into()
+	}
+}
+
+/// Agent daemon commands
+#[derive(Debug, Subcommand)]
+pub(crate) enum AgentCommands {
+	/// Make the running agent forget all its keys
+	Lock,
 }
 
 /// Clap value parsers
 mod parsers {
+	use std::time::{Duration, SystemTime};
+
+	use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
 	use duration_human::DurationHuman;
 	use mime::Mime;
 	use pgpaste_api_types::Visibility;
-	use sequoia_openpgp::KeyHandle;
+	use sequoia_openpgp::{Fingerprint, KeyHandle};
 
 	/// Convert a visibility string to a `Visibility` enum
 	pub(crate) fn to_api_visibility(visibility: &str) -> Result<Visibility, String> {
@@ -176,13 +510,51 @@ mod parsers {
 		handle.parse::<KeyHandle>().map_err(|err| err.to_string())
 	}
 
+	/// Convert a fingerprint string to a `Fingerprint` struct
+	pub(crate) fn to_fingerprint(fingerprint: &str) -> Result<Fingerprint, String> {
+		fingerprint
+			.parse::<Fingerprint>()
+			.map_err(|err| err.to_string())
+	}
+
 	/// Convert a mime type string to a `Mime` struct
 	pub(crate) fn to_mime(mime_type: &str) -> Result<Mime, String> {
 		mime_type.parse::<Mime>().map_err(|err| err.to_string())
 	}
 
-	/// Convert a duration string to a `Duration` struct
-	pub(crate) fn to_do<T>(_duration: &str) -> Result<T, String> {
-		panic!()
+	/// Convert an RFC 3339 or a friendly date string to a `SystemTime`
+	pub(crate) fn to_system_time(date: &str) -> Result<SystemTime, String> {
+		let date = date.trim();
+
+		if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+			return Ok(date.into());
+		}
+
+		if date == "tomorrow" {
+			return Ok(SystemTime::now() + Duration::from_secs(60 * 60 * 24));
+		}
+
+		if let Some(duration) = date.strip_prefix("in ") {
+			let duration = humantime::parse_duration(duration).map_err(|err| err.to_string())?;
+			return Ok(SystemTime::now() + duration);
+		}
+
+		let local = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"]
+			.iter()
+			.find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+			.or_else(|| {
+				NaiveDate::parse_from_str(date, "%Y-%m-%d")
+					.ok()
+					.and_then(|date| date.and_hms_opt(0, 0, 0))
+			})
+			.ok_or(
+				"Use an RFC 3339 date, `YYYY-MM-DD [HH:MM[:SS]]`, `tomorrow` or `in <duration>`",
+			)?;
+
+		Local
+			.from_local_datetime(&local)
+			.earliest()
+			.map(Into::into)
+			.ok_or_else(|| "This local time does not exist".into())
 	}
 }
@@ -0,0 +1,40 @@
+//! Implementation of the `agent` subcommand
+
+#[cfg(unix)]
+use serde::Serialize;
+
+use crate::{args::AgentArgs, config::Config};
+#[cfg(unix)]
+use crate::{args::AgentCommands, daemon, output::print_json};
+
+/// The result of `agent lock` in JSON
+#[cfg(unix)]
+#[derive(Debug, Serialize)]
+struct LockOutput {
+	/// Whether the agent forgot its keys
+	locked: bool,
+}
+
+/// Run the agent daemon or control the running one
+#[cfg(unix)]
+pub(crate) fn agent(args: &AgentArgs, config: &Config) -> eyre::Result<()> {
+	match args.command {
+		None => daemon::serve(args.ttl()),
+		Some(AgentCommands::Lock) => {
+			daemon::lock()?;
+			log::info!("Agent locked, all its keys were forgotten");
+
+			if config.format.is_json() {
+				return print_json(&LockOutput { locked: true });
+			}
+
+			Ok(())
+		}
+	}
+}
+
+/// Run the agent daemon or control the running one
+#[cfg(not(unix))]
+pub(crate) fn agent(_args: &AgentArgs, _config: &Config) -> eyre::Result<()> {
+	eyre::bail!("the agent is only available on Unix systems")
+}
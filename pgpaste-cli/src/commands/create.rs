@@ -1,25 +1,63 @@
 //! Implementation of the `create` subcommand
 
-use eyre::ContextCompat;
-use pgpaste_api_types::{
-	Visibility,
-	api::{CreateBody, CreateResponse},
-};
-use reqwest::{Method, StatusCode, Url, blocking::Client, header};
-use rpassword::prompt_password;
+use eyre::{Context, ContextCompat};
+use pgpaste_api_types::{Visibility, api::CreateBody};
+use pgpaste_client::Client;
+use sequoia_openpgp::KeyHandle;
+use serde::Serialize;
 
 use crate::{
+	ToEyreError,
 	args::CreateArgs,
+	block_on,
 	config::Config,
-	crypto::{SendHelper, encrypt, protect, sign},
+	crypto::{POLICY, SendHelper, encrypt, owns, protect, sign},
+	output::{print_json, rfc3339},
+	prompt,
 };
 
-// TODO: fix the need to enter the password two times for private pastes
+/// The author of the paste replied to, only trusted when their cert is already known
+/// since the server is the one telling who it is
+fn reply_recipient(signer: &str, config: &Config) -> eyre::Result<KeyHandle> {
+	let handle = signer.parse::<KeyHandle>().to_eyre()?;
+
+	let cert = config
+		.public_keys
+		.iter()
+		.chain(&config.private_keys)
+		.find(|cert| owns(cert, &handle))
+		.wrap_err_with(|| {
+			format!(
+				"the author {handle} of the paste is not in your public keys, \
+				 pass `--recipient {handle}` to reply to them anyway"
+			)
+		})?;
+
+	let user_id = cert
+		.with_policy(POLICY, None)
+		.and_then(|cert| cert.primary_userid())
+		.map(|uid| uid.userid().to_string())
+		.unwrap_or_default();
+	log::info!("Replying to {} {user_id}", cert.fingerprint());
+
+	Ok(handle)
+}
 
 #[allow(clippy::needless_pass_by_value)]
 /// Create a paste on the server
 pub(crate) fn create(args: CreateArgs, config: &Config) -> eyre::Result<()> {
-	let content = args.content()?;
+	let (content, mime) = args.content()?;
+
+	let visibility = args
+		.mode
+		.or(config.default_visibility)
+		.wrap_err("choose a visibility with `--mode` or set a `default_visibility`")?;
+	log::info!("Creating a {mime} paste");
+
+	if args.share_password && visibility != Visibility::Protected {
+		eyre::bail!("only protected pastes have a password to share");
+	}
+
 	let helper = SendHelper::new(
 		&config
 			.default_key
@@ -27,22 +65,71 @@ pub(crate) fn create(args: CreateArgs, config: &Config) -> eyre::Result<()> {
 			.wrap_err("you need to choose a key")?,
 		&config.private_keys,
 		&config.public_keys,
+		&config.agent_keys,
+		&config.unlocker,
+		&config.lookup,
 	)?;
 
-	let message = match args.mode {
+	let client = Client::new(config.server.clone());
+
+	let parent = args
+		.reply_to
+		.as_ref()
+		.map(|slug| block_on(client.get_paste(slug, None)))
+		.transpose()
+		.wrap_err("could not get the paste to reply to")?;
+
+	let mut shared_password = None;
+	let message = match visibility {
 		Visibility::Public => sign(&content, &helper)?,
 		Visibility::Private => {
-			let recipient = args
-				.recipient
-				.clone()
-				.or_else(|| config.default_key.clone())
-				.wrap_err("no recipient specified")?;
-
-			encrypt(&content, &helper, recipient, args.sign_private)?
+			// Replies to a private paste go back to its author by default
+			let parent_signer = parent
+				.as_ref()
+				.filter(|parent| parent.visibility == Visibility::Private)
+				.map(|parent| reply_recipient(&parent.signer, config))
+				.transpose()?;
+
+			let mut recipients = if args.recipients.is_empty() {
+				parent_signer
+					.or_else(|| config.default_key.clone())
+					.into_iter()
+					.collect()
+			} else {
+				args.recipients.clone()
+			};
+
+			if config.encrypt_to_self
+				&& let Some(default_key) = &config.default_key
+				&& !recipients.iter().any(|r| r.aliases(default_key))
+			{
+				recipients.push(default_key.clone());
+			}
+
+			if recipients.is_empty() {
+				eyre::bail!("no recipient specified");
+			}
+
+			encrypt(
+				&content,
+				&helper,
+				&recipients,
+				args.obscure,
+				args.sign_private,
+			)?
 		}
 		Visibility::Protected => {
-			let paste_password = prompt_password("Password: ")?;
-			protect(&content, &helper, &paste_password, args.sign_private)?
+			let paste_password = match args.password.password()? {
+				Some(password) => password,
+				None => prompt::password("Password to protect the paste: ", config.interactive)?
+					.as_str()
+					.into(),
+			};
+			let message = protect(&content, &helper, &paste_password, args.sign_private)?;
+			if args.share_password {
+				shared_password = Some(paste_password);
+			}
+			message
 		}
 	};
 
@@ -52,47 +139,59 @@ pub(crate) fn create(args: CreateArgs, config: &Config) -> eyre::Result<()> {
 		log::debug!("Wrote message to {}", path.display());
 	}
 
+	// The default lifetime only applies when no burn date is given at all
+	let burn_at = args.burn_at()?;
+	let burn_in = args.burn_in().or_else(|| {
+		burn_at
+			.is_none()
+			.then_some(config.default_lifetime)
+			.flatten()
+	});
+
 	let query = CreateBody {
 		slug: args.slug.clone(),
-		mime: args.mime.clone().unwrap_or(mime::TEXT_PLAIN),
-		visibility: args.mode,
-		burn_in: args.burn_in()?,
+		parent: args.reply_to.clone(),
+		mime,
+		visibility,
+		burn_in,
+		burn_at,
 		burn_after_read: args.burn_after_read,
 		message,
 	};
 
-	let query = rmp_serde::to_vec(&query)?;
-
-	let signed_query = sign(&query, &helper)?;
-
-	let res = post_paste(&config.server, &args, signed_query)?;
+	let res = block_on(client.create_paste(&query, args.overwrite, &helper))?;
 
 	log::info!("Your paste is available with the slug `{}`", res.slug);
+	log::info!(
+		"It will be burnt at {}",
+		humantime::format_rfc3339_seconds(res.burn_at)
+	);
+	if shared_password.is_some() {
+		log::warn!("Anyone with the URL below can read the paste");
+	}
 
-	Ok(())
-}
-
-/// Post a paste to the server
-fn post_paste(server: &Url, args: &CreateArgs, query: Vec<u8>) -> eyre::Result<CreateResponse> {
-	let client = Client::default();
+	let url = client.paste_url(&res.slug, shared_password.as_ref())?;
 
-	let method = if args.overwrite {
-		Method::PUT
-	} else {
-		Method::POST
-	};
+	if config.format.is_json() {
+		return print_json(&CreateOutput {
+			slug: &res.slug,
+			url: url.as_str(),
+			burn_at: rfc3339(res.burn_at),
+		});
+	}
 
-	let response = client
-		.request(method, server.join("/api/paste")?)
-		.header(header::CONTENT_TYPE, "application/pgp-signature")
-		.body(query)
-		.send()?;
+	println!("{url}");
 
-	let response = match response.status() {
-		StatusCode::CREATED => rmp_serde::from_slice::<CreateResponse>(&response.bytes()?)?,
-		StatusCode::CONFLICT => eyre::bail!("Paste name already exists"),
-		code => eyre::bail!("Unknown error: {}, {}", code, response.text()?),
-	};
+	Ok(())
+}
 
-	Ok(response)
+/// The result of `create` in JSON
+#[derive(Debug, Serialize)]
+struct CreateOutput<'a> {
+	/// The slug of the new paste
+	slug: &'a str,
+	/// The URL of the paste, with its password if it was shared
+	url: &'a str,
+	/// When the paste will be burnt
+	burn_at: String,
 }
@@ -0,0 +1,232 @@
+//! Implementation of the `edit` subcommand
+
+use std::{
+	env,
+	fs::{self, File},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::Path,
+	process::Command,
+};
+
+use eyre::{Context, ContextCompat};
+use pgpaste_api_types::{Visibility, api::CreateBody, bundle::BUNDLE_MIME};
+use pgpaste_client::{Client, parse_paste_url};
+use sequoia_openpgp::{
+	KeyHandle, KeyID, Packet,
+	parse::{PacketParser, PacketParserResult, Parse},
+};
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use zeroize::Zeroizing;
+
+use super::read::open_paste;
+use crate::{
+	ToEyreError,
+	args::EditArgs,
+	block_on,
+	config::Config,
+	crypto::{SendHelper, encrypt, protect, sign},
+	output::print_json,
+	prompt,
+};
+
+/// Edit a paste in the user editor and overwrite it with the same visibility,
+/// recipients and mime type
+pub(crate) fn edit(args: &EditArgs, config: &Config) -> eyre::Result<()> {
+	if !config.interactive {
+		eyre::bail!("editing a paste needs an interactive editor");
+	}
+
+	let location = parse_paste_url(&args.slug, &config.server)?;
+	let client = Client::new(location.server);
+	let slug = location.slug;
+	let paste = block_on(client.get_paste(&slug, None))?;
+
+	if paste.mime.essence_str() == BUNDLE_MIME {
+		eyre::bail!("bundles cannot be edited, create the paste again");
+	}
+
+	// The same password protects the new version
+	let password = match paste.visibility {
+		Visibility::Protected => Some(match args.password.password()?.or(location.password) {
+			Some(password) => password,
+			None => prompt::password("Password of the paste: ", config.interactive)?
+				.as_str()
+				.into(),
+		}),
+		Visibility::Public | Visibility::Private => None,
+	};
+
+	let recipients = match paste.visibility {
+		Visibility::Private => recipients(&paste.inner)?,
+		Visibility::Public | Visibility::Protected => Vec::new(),
+	};
+
+	let received = open_paste(&paste, password.clone(), config)?;
+	// A signed paste stays signed, the flag only signs a paste that was not
+	let sign_private = args.sign_private || !received.signatures.is_empty();
+	let content = Zeroizing::new(received.content);
+	let edited = Zeroizing::new(edit_in_editor(&content, &paste.mime)?);
+
+	if edited == content {
+		log::info!("No changes, `{slug}` was left as is");
+		return print_result(&client, &slug, false, config);
+	}
+
+	let helper = SendHelper::new(
+		&config
+			.default_key
+			.clone()
+			.wrap_err("you need to choose a key")?,
+		&config.private_keys,
+		&config.public_keys,
+		&config.agent_keys,
+		&config.unlocker,
+		&config.lookup,
+	)?;
+
+	let message = match (paste.visibility, &password) {
+		(Visibility::Public, _) => sign(&edited, &helper)?,
+		(Visibility::Private, _) => encrypt(&edited, &helper, &recipients, false, sign_private)?,
+		(Visibility::Protected, Some(password)) => {
+			protect(&edited, &helper, password, sign_private)?
+		}
+		(Visibility::Protected, None) => unreachable!("protected pastes always get a password"),
+	};
+
+	let query = CreateBody {
+		slug: Some(slug.clone()),
+		parent: paste.parent,
+		mime: paste.mime,
+		visibility: paste.visibility,
+		burn_in: None,
+		burn_at: Some(paste.burn_at),
+		burn_after_read: paste.burn_after_read,
+		message,
+	};
+
+	let res = block_on(client.create_paste(&query, true, &helper))?;
+
+	log::info!("Updated `{}`", res.slug);
+	print_result(&client, &res.slug, true, config)
+}
+
+/// The result of `edit` in JSON
+#[derive(Debug, Serialize)]
+struct EditOutput<'a> {
+	/// The slug of the paste
+	slug: &'a str,
+	/// The URL of the paste
+	url: &'a str,
+	/// Whether a new version of the paste was uploaded
+	changed: bool,
+}
+
+/// Print the URL of the edited paste
+fn print_result(client: &Client, slug: &str, changed: bool, config: &Config) -> eyre::Result<()> {
+	let url = client.paste_url(slug, None)?;
+
+	if config.format.is_json() {
+		return print_json(&EditOutput {
+			slug,
+			url: url.as_str(),
+			changed,
+		});
+	}
+
+	println!("{url}");
+	Ok(())
+}
+
+/// The recipients of an encrypted message, as listed in its PKESKs
+fn recipients(message: &[u8]) -> eyre::Result<Vec<KeyHandle>> {
+	let mut recipients = Vec::new();
+
+	let mut ppr = PacketParser::from_bytes(message).to_eyre()?;
+	while let PacketParserResult::Some(pp) = ppr {
+		// The recipients are all listed before the encrypted data
+		let Packet::PKESK(pkesk) = &pp.packet else {
+			break;
+		};
+
+		if pkesk.recipient().is_wildcard() {
+			eyre::bail!("the paste hides its recipients, they cannot be encrypted to again");
+		}
+
+		recipients.push(KeyHandle::from(pkesk.recipient().clone()));
+		ppr = pp.next().to_eyre()?.1;
+	}
+
+	if recipients.is_empty() {
+		eyre::bail!("the paste has no recipients");
+	}
+
+	recipients.dedup_by(|a, b| KeyID::from(a.clone()) == KeyID::from(b.clone()));
+
+	Ok(recipients)
+}
+
+/// Write the content to a private temporary file, open it in the user editor and return the result
+fn edit_in_editor(content: &[u8], mime: &mime::Mime) -> eyre::Result<Vec<u8>> {
+	// Editors pick the syntax highlighting from the extension
+	let suffix = mime_guess::get_mime_extensions(mime)
+		.and_then(|extensions| extensions.first())
+		.map(|extension| format!(".{extension}"))
+		.unwrap_or_default();
+
+	// Prefer the runtime directory which usually is a user-only tmpfs
+	let mut builder = tempfile::Builder::new();
+	builder.prefix("pgpaste-").suffix(&suffix);
+	let mut file = match dirs::runtime_dir() {
+		Some(directory) => builder.tempfile_in(directory),
+		None => builder.tempfile(),
+	}
+	.wrap_err("could not create a temporary file")?;
+
+	file.write_all(content)?;
+	file.flush()?;
+
+	let edited = run_editor(file.path()).and_then(|()| Ok(fs::read(file.path())?));
+
+	wipe(&file);
+
+	edited
+}
+
+/// Run `$VISUAL` or `$EDITOR` on a file and wait for it to exit
+fn run_editor(path: &Path) -> eyre::Result<()> {
+	let editor = env::var("VISUAL")
+		.or_else(|_| env::var("EDITOR"))
+		.unwrap_or_else(|_| "vi".into());
+
+	// The editor variable may contain arguments
+	let status = Command::new("sh")
+		.args(["-c", &format!("{editor} \"$1\""), "sh"])
+		.arg(path)
+		.status()
+		.wrap_err_with(|| format!("could not run `{editor}`"))?;
+
+	if !status.success() {
+		eyre::bail!("`{editor}` failed with {status}, the paste was left as is");
+	}
+
+	Ok(())
+}
+
+/// Overwrite the decrypted content before the temporary file is removed
+fn wipe(file: &NamedTempFile) {
+	if let Err(error) = zero_file(file.path()) {
+		log::warn!("could not wipe `{}`: {error}", file.path().display());
+	}
+}
+
+/// Overwrite a file with zeroes
+fn zero_file(path: &Path) -> io::Result<()> {
+	// Editors may replace the file instead of writing to it, open it again
+	let mut file = File::options().write(true).open(path)?;
+	let len = file.metadata()?.len();
+
+	file.seek(SeekFrom::Start(0))?;
+	io::copy(&mut io::repeat(0).take(len), &mut file)?;
+	file.sync_all()
+}
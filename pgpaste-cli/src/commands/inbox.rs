@@ -0,0 +1,112 @@
+//! Implementation of the `inbox` subcommand
+
+use eyre::ContextCompat;
+use pgpaste_api_types::api::InboxResponse;
+use pgpaste_client::Client;
+use serde::Serialize;
+
+use super::read::{Output, PasteOutput, open_paste, paste_output, show_content};
+use crate::{
+	args::InboxArgs,
+	block_on,
+	config::Config,
+	crypto::SendHelper,
+	output::{print_json, rfc3339},
+};
+
+#[allow(clippy::needless_pass_by_value)]
+/// List the private pastes addressed to the default key
+pub(crate) fn inbox(args: InboxArgs, config: &Config) -> eyre::Result<()> {
+	let helper = SendHelper::new(
+		&config
+			.default_key
+			.clone()
+			.wrap_err("you need to choose a key")?,
+		&config.private_keys,
+		&config.public_keys,
+		&config.agent_keys,
+		&config.unlocker,
+		&config.lookup,
+	)?;
+
+	let client = Client::new(config.server.clone());
+	let entries = block_on(client.inbox(&helper))?;
+
+	if config.format.is_json() {
+		return print_json(&inbox_output(&args, &client, entries, config)?);
+	}
+
+	if entries.is_empty() {
+		log::info!("Your inbox is empty");
+		return Ok(());
+	}
+
+	for entry in entries {
+		log::info!("`{}` from {} ({})", entry.slug, entry.signer, entry.mime);
+
+		if args.read {
+			let paste = block_on(client.get_paste(&entry.slug, None))?;
+			let received = open_paste(&paste, None, config)?;
+			show_content(&paste, &received.content, &Output::default())?;
+		}
+	}
+
+	Ok(())
+}
+
+/// The result of `inbox` in JSON
+#[derive(Debug, Serialize)]
+struct InboxOutput {
+	/// The pastes addressed to the default key
+	pastes: Vec<InboxEntryOutput>,
+}
+
+/// A paste of the inbox as reported in JSON
+#[derive(Debug, Serialize)]
+struct InboxEntryOutput {
+	/// The slug of the paste
+	slug: String,
+	/// The mime type of the content
+	mime: String,
+	/// The key that uploaded the paste
+	signer: String,
+	/// When the paste was created
+	created_at: String,
+	/// When the paste will be burnt
+	burn_at: String,
+	/// The decrypted paste, with `--read`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	paste: Option<PasteOutput>,
+}
+
+/// Describe the inbox for JSON output, with the decrypted pastes if asked to
+fn inbox_output(
+	args: &InboxArgs,
+	client: &Client,
+	entries: InboxResponse,
+	config: &Config,
+) -> eyre::Result<InboxOutput> {
+	let pastes = entries
+		.into_iter()
+		.map(|entry| {
+			let paste = if args.read {
+				let paste = block_on(client.get_paste(&entry.slug, None))?;
+				let received = open_paste(&paste, None, config)?;
+				Some(paste_output(&paste, &received, &Output::default(), config)?)
+			} else {
+				None
+			};
+
+			Ok(InboxEntryOutput {
+				slug: entry.slug,
+				mime: entry.mime.to_string(),
+				signer: entry.signer,
+				created_at: rfc3339(entry.created_at),
+				burn_at: rfc3339(entry.burn_at),
+				paste,
+			})
+		})
+		.collect::<eyre::Result<_>>()?;
+
+	Ok(InboxOutput { pastes })
+}
@@ -0,0 +1,204 @@
+//! Implementation of the `init` subcommand
+
+use std::{
+	fs::{self, OpenOptions},
+	path::{Path, PathBuf},
+	slice,
+};
+
+use pgpaste_client::{Client, crypto::verify};
+use sequoia_openpgp::{
+	Cert,
+	cert::CertBuilder,
+	packet::UserID,
+	parse::Parse,
+	serialize::{MarshalInto, Serialize as _},
+	types::KeyFlags,
+};
+use serde::Serialize;
+use zeroize::Zeroizing;
+
+use crate::{
+	ToEyreError,
+	args::InitArgs,
+	block_on,
+	commands::key::store_public_cert,
+	config::{Config, ConfigScheme},
+	crypto::{AgentKeys, ReceiveHelper, SendHelper, sign},
+	output::print_json,
+	prompt,
+};
+
+#[allow(clippy::needless_pass_by_value)]
+/// Set up the CLI config with a new or an existing key pair
+pub(crate) fn init(args: InitArgs, config: &Config) -> eyre::Result<()> {
+	let mut scheme = ConfigScheme::parse(&config.path)?;
+
+	if scheme
+		.profile_mut(config.profile.as_deref())?
+		.default_key
+		.is_some()
+		&& !args.force
+	{
+		eyre::bail!(
+			"`{}` already has a default key, use `--force` to replace it",
+			config.path.display()
+		);
+	}
+
+	let cert = match &args.import {
+		Some(path) => {
+			let cert = Cert::from_file(path).to_wrap_err("could not read certificate")?;
+			if !cert.is_tsk() {
+				eyre::bail!("`{}` does not contain secret keys", path.display());
+			}
+			cert
+		}
+		None => generate_cert(&args, config)?,
+	};
+	let public_cert = cert.clone().strip_secret_key_material();
+	let fingerprint = cert.fingerprint();
+
+	let (private_path, public_path) = write_cert(&config.path, &cert)?;
+
+	// Check that the key can be used before pointing the config to it, the
+	// self-signed cert doubles as the registration request
+	let public_bytes = public_cert.to_vec().to_eyre()?;
+	let agent_keys = AgentKeys::new();
+	let helper = SendHelper::new(
+		&cert.key_handle(),
+		slice::from_ref(&cert),
+		&[],
+		&agent_keys,
+		&config.unlocker,
+		&config.lookup,
+	)?;
+	let signed_cert = sign(&public_bytes, &helper)?;
+	let mut helper = ReceiveHelper::new(
+		&[],
+		slice::from_ref(&public_cert),
+		&agent_keys,
+		&config.unlocker,
+		&config.lookup,
+	)?;
+	if verify(&signed_cert, &mut helper)?.content != public_bytes {
+		eyre::bail!("sign and verify round trip returned a different content");
+	}
+	log::info!("Signed and verified a test message with {fingerprint}");
+
+	let profile = scheme.profile_mut(config.profile.as_deref())?;
+	profile.server = Some(config.server.to_string());
+	profile.default_key = Some(fingerprint.to_hex());
+	profile.add_private_key(private_path.clone());
+	profile.add_public_key(public_path.clone());
+	scheme.write(&config.path)?;
+	log::info!("Wrote config to `{}`", config.path.display());
+
+	if args.register {
+		let client = Client::new(config.server.clone());
+		block_on(client.register_key(signed_cert))?;
+		log::info!("Registered {fingerprint} with {}", config.server);
+	}
+
+	if config.format.is_json() {
+		return print_json(&InitOutput {
+			fingerprint: fingerprint.to_hex(),
+			config: &config.path,
+			private_key: &private_path,
+			public_key: &public_path,
+			registered: args.register,
+		});
+	}
+
+	Ok(())
+}
+
+/// The result of `init` in JSON
+#[derive(Debug, Serialize)]
+struct InitOutput<'a> {
+	/// The fingerprint of the new default key
+	fingerprint: String,
+	/// The written config file
+	config: &'a Path,
+	/// The file holding the secret keys
+	private_key: &'a Path,
+	/// The file holding the public cert
+	public_key: &'a Path,
+	/// Whether the cert was registered with the server
+	registered: bool,
+}
+
+/// Generate a new key pair, prompting for missing user information
+fn generate_cert(args: &InitArgs, config: &Config) -> eyre::Result<Cert> {
+	let name = match &args.name {
+		Some(name) => name.clone(),
+		None => prompt::line("Name: ", config.interactive)?,
+	};
+	let email = match &args.email {
+		Some(email) => email.clone(),
+		None => prompt::line("Email: ", config.interactive)?,
+	};
+	let userid = UserID::from_address(Some(name.as_str()), None, email.as_str())
+		.to_wrap_err("invalid name or email")?;
+
+	let password = new_password(config.interactive)?;
+
+	let (cert, revocation) = CertBuilder::new()
+		.add_userid(userid)
+		.set_primary_key_flags(KeyFlags::empty().set_certification().set_signing())
+		.add_subkey(
+			KeyFlags::empty()
+				.set_transport_encryption()
+				.set_storage_encryption(),
+			None,
+			None,
+		)
+		.set_password((!password.is_empty()).then(|| password.as_str().into()))
+		.generate()
+		.to_wrap_err("could not generate key")?;
+
+	let revocation_path =
+		ConfigScheme::keys_dir(&config.path)?.join(format!("{}.rev", cert.fingerprint()));
+	fs::write(&revocation_path, revocation.to_vec().to_eyre()?)?;
+	log::info!(
+		"Wrote a revocation certificate to `{}`, keep it somewhere safe",
+		revocation_path.display()
+	);
+
+	Ok(cert)
+}
+
+/// Asks for the password of the new key twice until both match or there were too
+/// many attempts
+fn new_password(interactive: bool) -> eyre::Result<Zeroizing<String>> {
+	for _ in 0..prompt::MAX_ATTEMPTS {
+		let password = prompt::password("Password to protect the new key: ", interactive)?;
+		if password == prompt::password("Repeat password: ", interactive)? {
+			return Ok(password);
+		}
+		log::error!("Passwords do not match, try again");
+	}
+
+	eyre::bail!("the passwords did not match too many times")
+}
+
+/// Write the secret and public parts of the cert next to the config file
+fn write_cert(config_path: &Path, cert: &Cert) -> eyre::Result<(PathBuf, PathBuf)> {
+	let directory = ConfigScheme::keys_dir(config_path)?;
+	let private_path = directory.join(format!("{}.pgp", cert.fingerprint()));
+
+	let mut options = OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+	let mut private_file = options.open(&private_path)?;
+	cert.as_tsk()
+		.armored()
+		.serialize(&mut private_file)
+		.to_eyre()?;
+
+	let public_path = store_public_cert(config_path, cert)?;
+
+	Ok((private_path, public_path))
+}
@@ -0,0 +1,449 @@
+//! Implementation of the `key` subcommand
+
+use std::{
+	fmt::Display,
+	fs::{self, File},
+	io::{self, Write},
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+
+use eyre::ContextCompat;
+use sequoia_openpgp::{
+	Cert, KeyHandle,
+	cert::CertParser,
+	parse::Parse,
+	serialize::{Serialize as _, SerializeInto},
+};
+use serde::Serialize;
+
+use crate::{
+	ToEyreError,
+	args::{KeyArgs, KeyCommands, KeyExportArgs, KeyImportArgs, KeySetDefaultArgs},
+	config::{Config, ConfigScheme, read_dir_sorted},
+	crypto::{CertLookup, POLICY, owns},
+	output::{print_json, rfc3339},
+};
+
+/// Manage the keys known to the CLI
+pub(crate) fn key(args: KeyArgs, config: &Config) -> eyre::Result<()> {
+	match args.command {
+		KeyCommands::List => list(config),
+		KeyCommands::Import(import_args) => import(&import_args, config),
+		KeyCommands::Export(export_args) => export(&export_args, config),
+		KeyCommands::SetDefault(set_default_args) => set_default(&set_default_args, config),
+		KeyCommands::Refresh => refresh(config),
+	}
+}
+
+/// A cert as reported in JSON
+#[derive(Debug, Serialize)]
+struct KeyOutput {
+	/// The fingerprint of the cert
+	fingerprint: String,
+	/// The primary user ID of the cert
+	user_id: String,
+	/// Whether this is the default key
+	default: bool,
+	/// Whether the cert is valid under the current policy
+	valid: bool,
+	/// Where the secret keys are: `yes`, `no` or `gpg-agent`
+	secret: &'static str,
+	/// The operations the valid keys are capable of
+	capabilities: Vec<&'static str>,
+	/// When the primary key expires, if it does
+	expires: Option<String>,
+	/// Whether the primary key has expired
+	expired: bool,
+}
+
+/// The result of `key list` in JSON
+#[derive(Debug, Serialize)]
+struct ListOutput {
+	/// The loaded certs, private ones first
+	keys: Vec<KeyOutput>,
+}
+
+/// Show the loaded certs, their capabilities and expiry
+fn list(config: &Config) -> eyre::Result<()> {
+	let public_only = config.public_keys.iter().filter(|cert| {
+		!config
+			.private_keys
+			.iter()
+			.any(|private| private.fingerprint() == cert.fingerprint())
+	});
+
+	let keys = config
+		.private_keys
+		.iter()
+		.chain(public_only)
+		.map(|cert| describe(cert, config))
+		.collect::<Vec<_>>();
+
+	if config.format.is_json() {
+		return print_json(&ListOutput { keys });
+	}
+
+	for key in keys {
+		log::info!(
+			"{} {}{}",
+			key.fingerprint,
+			key.user_id,
+			if key.default { " [default]" } else { "" }
+		);
+
+		if !key.valid {
+			log::info!("    invalid under the current policy");
+			continue;
+		}
+
+		let capabilities = if key.capabilities.is_empty() {
+			"none".into()
+		} else {
+			key.capabilities.join(", ")
+		};
+		log::info!("    secret: {}, capabilities: {capabilities}", key.secret);
+
+		match key.expires {
+			Some(time) if key.expired => log::info!("    expired: {time}"),
+			Some(time) => log::info!("    expires: {time}"),
+			None => log::info!("    expires: never"),
+		}
+	}
+
+	Ok(())
+}
+
+/// Describe a cert for the key listing
+fn describe(cert: &Cert, config: &Config) -> KeyOutput {
+	let default = config
+		.default_key
+		.as_ref()
+		.is_some_and(|handle| handle.aliases(cert.key_handle()));
+
+	let secret = if config.agent_keys.contains_key(&cert.fingerprint()) {
+		"gpg-agent"
+	} else if cert.is_tsk() {
+		"yes"
+	} else {
+		"no"
+	};
+
+	let valid_cert = cert.with_policy(POLICY, None).ok();
+	let expiration = valid_cert
+		.as_ref()
+		.and_then(|cert| cert.primary_key().key_expiration_time());
+
+	KeyOutput {
+		fingerprint: cert.fingerprint().to_hex(),
+		user_id: identity(cert),
+		default,
+		valid: valid_cert.is_some(),
+		secret,
+		capabilities: capabilities(cert),
+		expires: expiration.map(rfc3339),
+		expired: expiration.is_some_and(|time| time <= SystemTime::now()),
+	}
+}
+
+/// A cert imported or updated by `key import` or `key refresh`
+#[derive(Debug, Serialize)]
+struct ImportedOutput {
+	/// The fingerprint of the cert
+	fingerprint: String,
+	/// The primary user ID of the cert
+	user_id: String,
+}
+
+impl ImportedOutput {
+	/// Describe a cert
+	fn new(cert: &Cert) -> Self {
+		Self {
+			fingerprint: cert.fingerprint().to_hex(),
+			user_id: identity(cert),
+		}
+	}
+}
+
+/// The result of `key import` in JSON
+#[derive(Debug, Serialize)]
+struct ImportOutput {
+	/// The imported certs
+	imported: Vec<ImportedOutput>,
+}
+
+/// Import certs from a file, the keyservers or WKD in the public keys store
+fn import(args: &KeyImportArgs, config: &Config) -> eyre::Result<()> {
+	let certs = if Path::new(&args.source).exists() {
+		CertParser::from_file(&args.source)
+			.to_wrap_err("could not read certificates")?
+			.collect::<sequoia_openpgp::Result<Vec<_>>>()
+			.to_wrap_err("could not parse certificates")?
+	} else if args.source.contains('@') {
+		config.lookup.search(&args.source)?
+	} else {
+		let handle = args
+			.source
+			.parse::<KeyHandle>()
+			.to_wrap_err("source is neither a file, a fingerprint nor an email address")?;
+
+		vec![config.lookup.get(&handle)?]
+	};
+
+	let mut scheme = ConfigScheme::parse(&config.path)?;
+	let profile = scheme.profile_mut(config.profile.as_deref())?;
+
+	let mut imported = Vec::new();
+	for cert in certs {
+		let path = store_public_cert(&config.path, &cert)?;
+		profile.add_public_key(path);
+
+		log::info!("Imported {} {}", cert.fingerprint(), identity(&cert));
+		imported.push(ImportedOutput::new(&cert));
+	}
+
+	scheme.write(&config.path)?;
+
+	if config.format.is_json() {
+		return print_json(&ImportOutput { imported });
+	}
+
+	Ok(())
+}
+
+/// The result of `key export` in JSON
+#[derive(Debug, Serialize)]
+struct ExportOutput<'a> {
+	/// The fingerprint of the cert
+	fingerprint: String,
+	/// Whether the secret keys were exported
+	secret: bool,
+	/// The armored cert, unless it was written to a file
+	#[serde(skip_serializing_if = "Option::is_none")]
+	armored: Option<String>,
+	/// The file the cert was written to
+	#[serde(skip_serializing_if = "Option::is_none")]
+	written_to: Option<&'a Path>,
+}
+
+/// Write a cert to the standard output or to a file
+fn export(args: &KeyExportArgs, config: &Config) -> eyre::Result<()> {
+	let cert = if args.secret {
+		find_cert(&config.private_keys, &args.key)
+			.wrap_err("no secret key matches the given handle")?
+	} else {
+		find_cert(&config.private_keys, &args.key)
+			.or_else(|| find_cert(&config.public_keys, &args.key))
+			.wrap_err("no key matches the given handle")?
+	};
+
+	if args.secret && config.agent_keys.contains_key(&cert.fingerprint()) {
+		eyre::bail!("the secret keys are held by gpg-agent, export them with GnuPG");
+	}
+
+	let armored = if args.secret {
+		cert.as_tsk().armored().to_vec().to_eyre()?
+	} else {
+		cert.armored().to_vec().to_eyre()?
+	};
+
+	if let Some(path) = &args.output {
+		fs::write(path, &armored)?;
+	}
+
+	if config.format.is_json() {
+		return print_json(&ExportOutput {
+			fingerprint: cert.fingerprint().to_hex(),
+			secret: args.secret,
+			armored: args
+				.output
+				.is_none()
+				.then(|| String::from_utf8_lossy(&armored).into_owned()),
+			written_to: args.output.as_deref(),
+		});
+	}
+
+	if args.output.is_none() {
+		io::stdout().write_all(&armored)?;
+	}
+
+	Ok(())
+}
+
+/// Change the default key used for signing and decrypting
+fn set_default(args: &KeySetDefaultArgs, config: &Config) -> eyre::Result<()> {
+	let cert = find_cert(&config.private_keys, &args.key)
+		.wrap_err("the default key must be one of the private keys")?;
+
+	let mut scheme = ConfigScheme::parse(&config.path)?;
+	scheme.profile_mut(config.profile.as_deref())?.default_key = Some(cert.fingerprint().to_hex());
+	scheme.write(&config.path)?;
+
+	log::info!(
+		"Default key is now {} {}",
+		cert.fingerprint(),
+		identity(cert)
+	);
+
+	if config.format.is_json() {
+		return print_json(&ImportedOutput::new(cert));
+	}
+
+	Ok(())
+}
+
+/// The result of `key refresh` in JSON
+#[derive(Debug, Default, Serialize)]
+struct RefreshOutput {
+	/// The certs updated from the keyservers
+	refreshed: Vec<ImportedOutput>,
+	/// The files or certs that could not be refreshed
+	errors: Vec<RefreshError>,
+}
+
+/// A file or cert that could not be refreshed
+#[derive(Debug, Serialize)]
+struct RefreshError {
+	/// The file or the fingerprint of the cert
+	source: String,
+	/// Why it could not be refreshed
+	error: String,
+}
+
+impl RefreshOutput {
+	/// Log and record an error
+	fn error(&mut self, source: String, error: &dyn Display) {
+		log::error!("could not refresh {source}: {error}");
+		self.errors.push(RefreshError {
+			source,
+			error: error.to_string(),
+		});
+	}
+}
+
+/// Update the stored public certs with the keyservers and WKD versions
+fn refresh(config: &Config) -> eyre::Result<()> {
+	let mut scheme = ConfigScheme::parse(&config.path)?;
+	let profile = scheme.profile_mut(config.profile.as_deref())?;
+
+	let mut output = RefreshOutput::default();
+	for path in profile.public_keys.clone().unwrap_or_default() {
+		refresh_path(&path, &config.lookup, &mut output);
+	}
+
+	if config.format.is_json() {
+		return print_json(&output);
+	}
+
+	Ok(())
+}
+
+/// Refresh a certificate file or all the files of a directory
+fn refresh_path(path: &Path, lookup: &CertLookup, output: &mut RefreshOutput) {
+	if !path.is_dir() {
+		if let Err(error) = refresh_file(path, lookup, output) {
+			output.error(format!("`{}`", path.display()), &error);
+		}
+		return;
+	}
+
+	match read_dir_sorted(path) {
+		Ok(entries) => entries
+			.iter()
+			.for_each(|entry| refresh_path(entry, lookup, output)),
+		Err(error) => output.error(format!("`{}`", path.display()), &error),
+	}
+}
+
+/// Merge the looked up version of every cert of a file in place
+fn refresh_file(path: &Path, lookup: &CertLookup, output: &mut RefreshOutput) -> eyre::Result<()> {
+	let mut certs = CertParser::from_file(path)
+		.to_eyre()?
+		.collect::<sequoia_openpgp::Result<Vec<_>>>()
+		.to_eyre()?;
+
+	let mut refreshed = false;
+	for cert in &mut certs {
+		match lookup.refresh(cert) {
+			Ok(update) => {
+				*cert = update;
+				refreshed = true;
+				log::info!("Refreshed {} {}", cert.fingerprint(), identity(cert));
+				output.refreshed.push(ImportedOutput::new(cert));
+			}
+			Err(error) => output.error(cert.fingerprint().to_hex(), &error),
+		}
+	}
+
+	if !refreshed {
+		return Ok(());
+	}
+
+	let mut file = File::create(path)?;
+	for cert in &certs {
+		cert.armored().serialize(&mut file).to_eyre()?;
+	}
+
+	Ok(())
+}
+
+/// Write the public part of a cert in the keys store and return its path
+pub(super) fn store_public_cert(config_path: &Path, cert: &Cert) -> eyre::Result<PathBuf> {
+	let path = ConfigScheme::keys_dir(config_path)?.join(format!("{}.pub.pgp", cert.fingerprint()));
+
+	// Keep what we already know about the cert, like third-party certifications
+	let cert = match Cert::from_file(&path) {
+		Ok(stored) if stored.fingerprint() == cert.fingerprint() => {
+			stored.merge_public(cert.clone()).to_eyre()?
+		}
+		_ => cert.clone().strip_secret_key_material(),
+	};
+
+	cert.armored()
+		.serialize(&mut File::create(&path)?)
+		.to_eyre()?;
+
+	Ok(path)
+}
+
+/// Find the cert that owns the given key handle
+fn find_cert<'a>(certs: &'a [Cert], handle: &KeyHandle) -> Option<&'a Cert> {
+	certs.iter().find(|cert| owns(cert, handle))
+}
+
+/// The primary user ID of a cert, if any
+fn identity(cert: &Cert) -> String {
+	cert.with_policy(POLICY, None)
+		.and_then(|cert| cert.primary_userid())
+		.map(|uid| uid.userid().to_string())
+		.unwrap_or_default()
+}
+
+/// The operations the valid keys of a cert are capable of
+fn capabilities(cert: &Cert) -> Vec<&'static str> {
+	let keys = cert
+		.keys()
+		.with_policy(POLICY, None)
+		.alive()
+		.revoked(false)
+		.collect::<Vec<_>>();
+
+	let mut capabilities = Vec::new();
+	if keys.iter().any(|ka| ka.for_certification()) {
+		capabilities.push("certify");
+	}
+	if keys.iter().any(|ka| ka.for_signing()) {
+		capabilities.push("sign");
+	}
+	if keys
+		.iter()
+		.any(|ka| ka.for_transport_encryption() || ka.for_storage_encryption())
+	{
+		capabilities.push("encrypt");
+	}
+	if keys.iter().any(|ka| ka.for_authentication()) {
+		capabilities.push("authenticate");
+	}
+
+	capabilities
+}
@@ -1,7 +1,17 @@
 //! Implementations of the CLI commands
 
+mod agent;
 mod create;
+mod edit;
+mod inbox;
+mod init;
+mod key;
 mod read;
 
+pub(crate) use agent::agent;
 pub(crate) use create::create;
+pub(crate) use edit::edit;
+pub(crate) use inbox::inbox;
+pub(crate) use init::init;
+pub(crate) use key::key;
 pub(crate) use read::read;
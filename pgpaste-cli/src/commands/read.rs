@@ -1,62 +1,380 @@
 //! Implementation of the `read` subcommand
 
-use std::borrow::Cow;
+use std::{
+	ffi::OsStr,
+	fs::{self, OpenOptions},
+	io::{self, IsTerminal, Write},
+	path::{Path, PathBuf},
+};
 
-use pgpaste_api_types::{Visibility, api::ReadResponse};
-use reqwest::{
-	StatusCode, Url,
-	blocking::Client,
-	header::{self, HeaderValue},
+use encoding_rs::{Encoding, UTF_8};
+use eyre::Context;
+use mime::Mime;
+use pgpaste_api_types::{
+	Visibility,
+	api::ReadResponse,
+	bundle::{self, Manifest},
 };
+use pgpaste_client::{Client, parse_paste_url};
+use sequoia_openpgp::{Fingerprint, crypto::Password};
+use serde::Serialize;
 
 use crate::{
 	args::ReadArgs,
+	block_on,
 	config::Config,
-	crypto::{ReceiveHelper, decrypt, verify},
+	crypto::{ReceiveHelper, Received, open},
+	output::{Content, print_json, rfc3339},
 };
 
 #[allow(clippy::needless_pass_by_value)]
 /// Read a paste from the server
 pub(crate) fn read(args: ReadArgs, config: &Config) -> eyre::Result<()> {
-	let paste = get_paste(config.server.clone(), &args.slug, &args)?;
+	let location = parse_paste_url(&args.slug, &config.server)?;
+	let client = Client::new(location.server);
 
-	let helper = ReceiveHelper::new(&config.private_keys, &config.public_keys)?;
+	let paste = block_on(client.get_paste(&location.slug, args.rev))?;
+	let password = args.password.password()?.or(location.password);
+	let received = open_paste(&paste, password, config)?;
 
-	let content = match paste.visibility {
-		Visibility::Public => verify(&paste.inner, helper)?,
-		Visibility::Protected | Visibility::Private => decrypt(&paste.inner, helper)?,
+	let output = Output {
+		extract: args.extract.as_deref(),
+		file: args.output.as_deref(),
+		force: args.force,
 	};
 
-	let content = match paste.mime {
-		text if text == mime::TEXT_PLAIN => String::from_utf8_lossy(&content),
-		_ => Cow::Owned(format!("{:?}", &content)),
-	};
+	if !config.format.is_json() {
+		report_signatures(&received, config);
+	}
+	check_signatures(&received, &args)?;
+
+	if config.format.is_json() {
+		return print_json(&paste_output(&paste, &received, &output, config)?);
+	}
+
+	show_content(&paste, &received.content, &output)
+}
+
+/// Log who signed the content of a paste
+fn report_signatures(received: &Received, config: &Config) {
+	if received.signatures.is_empty() {
+		log::info!("The content of the paste is not signed");
+	}
+
+	for signature in &received.signatures {
+		let user_id = signature
+			.user_id
+			.as_deref()
+			.unwrap_or("a cert without user ID");
+		let made_at = signature.created_at.map_or_else(
+			|| "at an unknown time".to_owned(),
+			|time| format!("at {}", humantime::format_rfc3339_seconds(time)),
+		);
+
+		if is_known(&signature.fingerprint, config) {
+			log::info!(
+				"Good signature from {user_id} ({}) made {made_at}",
+				signature.fingerprint.to_hex()
+			);
+		} else {
+			log::warn!(
+				"Good signature from {user_id} ({}) made {made_at}, but this cert is not in your public keys",
+				signature.fingerprint.to_hex()
+			);
+		}
+	}
+}
+
+/// Fail if the content of a paste is not signed as the arguments require
+fn check_signatures(received: &Received, args: &ReadArgs) -> eyre::Result<()> {
+	if let Some(expected) = &args.expect_signer
+		&& !received.is_signed_by(expected)
+	{
+		eyre::bail!("the paste is not signed by {}", expected.to_hex());
+	}
 
-	log::info!("Your paste content:");
-	log::info!("{content}");
+	if args.require_signed && received.signatures.is_empty() {
+		eyre::bail!("the paste is not signed");
+	}
 
 	Ok(())
 }
 
-/// Get a paste from the server
-fn get_paste(mut server: Url, slug: &str, _args: &ReadArgs) -> eyre::Result<ReadResponse> {
-	let client = Client::default();
+/// Whether a signer cert is one of our public keys
+fn is_known(fingerprint: &Fingerprint, config: &Config) -> bool {
+	config
+		.public_keys
+		.iter()
+		.any(|cert| &cert.fingerprint() == fingerprint)
+}
+
+/// Verify or decrypt the inner message of a paste, a protected one with the given
+/// password or a prompted one
+pub(super) fn open_paste(
+	paste: &ReadResponse,
+	password: Option<Password>,
+	config: &Config,
+) -> eyre::Result<Received> {
+	let mut helper = ReceiveHelper::new(
+		&config.private_keys,
+		&config.public_keys,
+		&config.agent_keys,
+		&config.unlocker,
+		&config.lookup,
+	)?
+	.with_password(password);
+
+	open(paste, &mut helper)
+}
+
+/// Where to write the content of a paste
+#[derive(Debug, Default)]
+pub(super) struct Output<'a> {
+	/// Extract the files of a bundle in this directory
+	pub(super) extract: Option<&'a Path>,
+	/// Write the content to this file instead of the standard output
+	pub(super) file: Option<&'a Path>,
+	/// Write binary content to a terminal anyway
+	pub(super) force: bool,
+}
+
+/// Write the content of a paste or extract its files if it is a bundle
+pub(super) fn show_content(
+	paste: &ReadResponse,
+	content: &[u8],
+	output: &Output,
+) -> eyre::Result<()> {
+	log::info!(
+		"`{}`: {:?} paste, {}, {} bytes, signed by {}",
+		paste.slug,
+		paste.visibility,
+		paste.mime,
+		content.len(),
+		paste.signer
+	);
 
-	server.set_path(&format!("/api/paste/{slug}"));
+	if bundle::is_bundle(&paste.mime) {
+		if output.file.is_some() {
+			eyre::bail!("Bundle pastes cannot be written to a single file, use `--extract`");
+		}
 
-	let response = client.get(server).send()?;
+		let (manifest, contents) = bundle::decode(content)?;
 
-	match response.status() {
-		StatusCode::OK => {
-			if let Some(content_type) = response.headers().get(header::CONTENT_TYPE) {
-				if HeaderValue::from_str(mime::APPLICATION_MSGPACK.as_ref())? != content_type {
-					eyre::bail!("Invalid content type");
+		match output.extract {
+			Some(directory) => extract_bundle(directory, &manifest, &contents)?,
+			None => {
+				log::info!(
+					"Your paste is a bundle of {} files:",
+					manifest.entries.len()
+				);
+				for entry in &manifest.entries {
+					log::info!("{} ({}, {} bytes)", entry.name, entry.mime, entry.size);
 				}
-			};
+				log::info!("Use `--extract <DIR>` to write them to disk");
+			}
+		}
+
+		return Ok(());
+	} else if output.extract.is_some() {
+		eyre::bail!("Only bundle pastes can be extracted");
+	}
+
+	// Files always get the exact bytes of the paste
+	if let Some(path) = output.file {
+		fs::write(path, content)
+			.wrap_err_with(|| format!("Could not write `{}`", path.display()))?;
+		log::info!("Wrote the paste content to `{}`", path.display());
+
+		return Ok(());
+	}
+
+	let mut stdout = io::stdout().lock();
 
-			Ok(rmp_serde::from_slice(&response.bytes()?)?)
+	if !stdout.is_terminal() {
+		stdout.write_all(content)?;
+		return Ok(stdout.flush()?);
+	}
+
+	if !is_textual(&paste.mime) && !output.force {
+		eyre::bail!(
+			"Refusing to write a {} paste to the terminal, use `--output <FILE>` or `--force`",
+			paste.mime
+		);
+	}
+
+	// Terminals expect UTF-8, other text charsets are converted for display
+	let encoding = paste
+		.mime
+		.get_param(mime::CHARSET)
+		.and_then(|charset| Encoding::for_label(charset.as_str().as_bytes()))
+		.unwrap_or(UTF_8);
+
+	if encoding == UTF_8 || paste.mime.type_() != mime::TEXT {
+		stdout.write_all(content)?;
+	} else {
+		let (text, _, had_errors) = encoding.decode(content);
+		if had_errors {
+			log::warn!("The paste content is not valid {}", encoding.name());
+		}
+		stdout.write_all(text.as_bytes())?;
+	}
+
+	Ok(stdout.flush()?)
+}
+
+/// A paste as reported in JSON
+#[derive(Debug, Serialize)]
+pub(super) struct PasteOutput {
+	/// The slug of the paste
+	slug: String,
+	/// The visibility of the paste
+	visibility: Visibility,
+	/// The mime type of the content
+	mime: String,
+	/// When the paste will be burnt
+	burn_at: String,
+	/// The key that uploaded the paste
+	signer: String,
+	/// The paste this one replies to
+	parent: Option<String>,
+	/// The pastes replying to this one
+	children: Vec<String>,
+	/// The good signatures over the content
+	signatures: Vec<SignatureOutput>,
+	/// The content, unless it was written to disk or is a bundle
+	#[serde(flatten)]
+	content: Option<Content>,
+	/// The files of a bundle, unless they were extracted
+	#[serde(skip_serializing_if = "Option::is_none")]
+	files: Option<Vec<FileOutput>>,
+	/// Where the content or the files were written
+	#[serde(skip_serializing_if = "Option::is_none")]
+	written_to: Option<PathBuf>,
+}
+
+/// A good signature as reported in JSON
+#[derive(Debug, Serialize)]
+struct SignatureOutput {
+	/// The cert that made the signature
+	fingerprint: String,
+	/// The primary user ID of the cert
+	user_id: Option<String>,
+	/// When the signature was made
+	created_at: Option<String>,
+	/// Whether the cert is one of our public keys
+	known: bool,
+}
+
+/// A file of a bundle as reported in JSON
+#[derive(Debug, Serialize)]
+pub(super) struct FileOutput {
+	/// The name of the file
+	name: String,
+	/// The mime type of the file
+	mime: String,
+	/// The content of the file
+	#[serde(flatten)]
+	content: Content,
+}
+
+/// Describe a paste for JSON output, writing its content or files to disk if asked to
+pub(super) fn paste_output(
+	paste: &ReadResponse,
+	received: &Received,
+	output: &Output,
+	config: &Config,
+) -> eyre::Result<PasteOutput> {
+	let mut content = None;
+	let mut files = None;
+	let mut written_to = None;
+
+	if bundle::is_bundle(&paste.mime) {
+		if output.file.is_some() {
+			eyre::bail!("Bundle pastes cannot be written to a single file, use `--extract`");
+		}
+
+		let (manifest, contents) = bundle::decode(&received.content)?;
+
+		if let Some(directory) = output.extract {
+			extract_bundle(directory, &manifest, &contents)?;
+			written_to = Some(directory.to_owned());
+		} else {
+			let entries = manifest.entries.iter().zip(contents);
+			files = Some(
+				entries
+					.map(|(entry, content)| FileOutput {
+						name: entry.name.clone(),
+						mime: entry.mime.to_string(),
+						content: Content::new(content),
+					})
+					.collect(),
+			);
+		}
+	} else if output.extract.is_some() {
+		eyre::bail!("Only bundle pastes can be extracted");
+	} else if let Some(path) = output.file {
+		fs::write(path, &received.content)
+			.wrap_err_with(|| format!("Could not write `{}`", path.display()))?;
+		written_to = Some(path.to_owned());
+	} else {
+		content = Some(Content::new(&received.content));
+	}
+
+	Ok(PasteOutput {
+		slug: paste.slug.clone(),
+		visibility: paste.visibility,
+		mime: paste.mime.to_string(),
+		burn_at: rfc3339(paste.burn_at),
+		signer: paste.signer.clone(),
+		parent: paste.parent.clone(),
+		children: paste.children.clone(),
+		signatures: received
+			.signatures
+			.iter()
+			.map(|signature| SignatureOutput {
+				fingerprint: signature.fingerprint.to_hex(),
+				user_id: signature.user_id.clone(),
+				created_at: signature.created_at.map(rfc3339),
+				known: is_known(&signature.fingerprint, config),
+			})
+			.collect(),
+		content,
+		files,
+		written_to,
+	})
+}
+
+/// Whether a mime type describes content that can be shown in a terminal
+fn is_textual(mime: &Mime) -> bool {
+	mime.type_() == mime::TEXT
+		|| mime.subtype() == mime::JSON
+		|| mime.subtype() == mime::XML
+		|| mime.subtype() == mime::JAVASCRIPT
+		|| mime
+			.suffix()
+			.is_some_and(|suffix| suffix == mime::JSON || suffix == mime::XML)
+}
+
+/// Write each file of a bundle in the given directory
+fn extract_bundle(directory: &Path, manifest: &Manifest, contents: &[&[u8]]) -> eyre::Result<()> {
+	fs::create_dir_all(directory)?;
+
+	for (entry, content) in manifest.entries.iter().zip(contents) {
+		// Do not let a bundle write outside of the chosen directory
+		if Path::new(&entry.name).file_name() != Some(OsStr::new(&entry.name)) {
+			eyre::bail!("Bundle contains an invalid file name `{}`", entry.name);
 		}
-		StatusCode::NOT_FOUND => eyre::bail!("Paste not found"),
-		code => eyre::bail!("Unknown error: {}, {}", code, response.text()?),
+
+		let path = directory.join(&entry.name);
+		OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(&path)
+			.wrap_err_with(|| format!("Could not create `{}`", path.display()))?
+			.write_all(content)?;
+
+		log::info!("Extracted {}", path.display());
 	}
+
+	Ok(())
 }
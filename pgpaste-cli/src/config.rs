@@ -1,28 +1,99 @@
 //! CLI parsed configuration
 
-use std::{fs::read_to_string, path::PathBuf};
+use std::{
+	collections::BTreeMap,
+	env,
+	fs::{canonicalize, create_dir_all, read_dir, read_to_string, write},
+	io,
+	path::{Path, PathBuf},
+	time::Duration,
+};
 
-use dirs::config_local_dir;
-use eyre::Context;
-use reqwest::Url;
-use sequoia_openpgp::{Cert, KeyHandle, parse::Parse};
+use dirs::{config_local_dir, data_dir};
+use duration_human::DurationHuman;
+use eyre::{Context, ContextCompat};
+use pgpaste_api_types::Visibility;
+use pgpaste_client::Url;
+use sequoia_openpgp::{Cert, Fingerprint, KeyHandle, cert::CertParser, parse::Parse};
 use serde::{Deserialize, Serialize};
 
-use crate::{ToEyreError, args::PGPasteArgs};
+use crate::{
+	ToEyreError,
+	args::PGPasteArgs,
+	crypto::{AgentKey, AgentKeys, CertLookup, KeyUnlocker},
+	output::Format,
+};
 
 /// Config scheme as represented on disk
 #[derive(Debug, Serialize, Deserialize)]
-struct ConfigScheme {
+pub(crate) struct ConfigScheme {
+	/// The settings used when no profile is selected, and by profiles that do not set them
+	#[serde(flatten)]
+	pub(crate) base: ProfileScheme,
+
+	/// Whether private pastes should also be encrypted to the default key
+	#[serde(default)]
+	pub(crate) encrypt_to_self: bool,
+	/// Whether to also use the public keys of the shared `pgp.cert.d` directory
+	#[serde(default)]
+	pub(crate) use_cert_d: bool,
+
+	/// The keyservers unknown certs are looked up on, in order, e.g.
+	/// `hkps://keys.openpgp.org` or `hkp://localhost:11371`
+	pub(crate) keyservers: Option<Vec<String>>,
+	/// Whether to look certs up in the Web Key Directory of email addresses
+	pub(crate) use_wkd: Option<bool>,
+	/// Never look certs up on the network, only in the cert cache
+	#[serde(default)]
+	pub(crate) offline: bool,
+	/// Where looked up certs are kept, in the user cache directory if unset
+	pub(crate) cert_cache: Option<PathBuf>,
+	/// How long a cached cert is used before being looked up again, e.g. `1day`
+	pub(crate) cert_cache_refresh: Option<String>,
+
+	/// The profile to use when none is given on the command line
+	pub(crate) default_profile: Option<String>,
+
+	/// Where the secret material of each key lives, by fingerprint
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub(crate) keys: BTreeMap<String, KeyBackend>,
+	/// Named sets of settings, e.g. one per pgpaste instance
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub(crate) profiles: BTreeMap<String, ProfileScheme>,
+}
+
+/// The settings that can change from one profile to another
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct ProfileScheme {
 	/// The pgpaste server to query
-	#[serde(default = "default_server")]
-	server: String,
+	pub(crate) server: Option<String>,
 
 	/// The default key to use when encrypting or signing pastes
-	default_key: Option<String>,
-	/// A set of private keys to use when encrypting or decrypting pastes
-	private_keys: Option<Vec<PathBuf>>,
-	/// A set of public keys to use when signing or verifying pastes
-	public_keys: Option<Vec<PathBuf>>,
+	pub(crate) default_key: Option<String>,
+	/// A set of private keys to use when encrypting or decrypting pastes, files
+	/// may be keyrings and directories are read recursively
+	pub(crate) private_keys: Option<Vec<PathBuf>>,
+	/// A set of public keys to use when signing or verifying pastes, files may
+	/// be keyrings and directories are read recursively
+	pub(crate) public_keys: Option<Vec<PathBuf>>,
+
+	/// The visibility of created pastes when `--mode` is not given
+	pub(crate) default_visibility: Option<Visibility>,
+	/// The lifetime of created pastes when no burn date is given, e.g. `7days`
+	pub(crate) default_lifetime: Option<String>,
+}
+
+/// Where the secret material of a key lives
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub(crate) enum KeyBackend {
+	/// Loaded from the `private_keys` files
+	File,
+	/// Held by a `gpg-agent`, the cert is looked up in the `public_keys`
+	GpgAgent {
+		/// The GnuPG home directory of the agent, the default one if unset
+		homedir: Option<PathBuf>,
+	},
 }
 
 /// The default public instance of a pgpaste server
@@ -31,19 +102,22 @@ fn default_server() -> String {
 }
 
 impl ConfigScheme {
-	/// Parse the config file for further processing
-	fn parse(args: &PGPasteArgs) -> eyre::Result<Self> {
-		let path = if let Some(ref path) = args.config {
-			path.clone()
-		} else {
-			let Some(mut path) = config_local_dir() else {
-				eyre::bail!("Could not find config directory");
-			};
+	/// The path of the config file, either given or in the user config directory
+	pub(crate) fn path(args: &PGPasteArgs) -> eyre::Result<PathBuf> {
+		if let Some(ref path) = args.config {
+			return Ok(path.clone());
+		}
 
-			path.push("pgpaste.toml");
-			path
+		let Some(mut path) = config_local_dir() else {
+			eyre::bail!("Could not find config directory");
 		};
 
+		path.push("pgpaste.toml");
+		Ok(path)
+	}
+
+	/// Parse the config file for further processing
+	pub(crate) fn parse(path: &Path) -> eyre::Result<Self> {
 		let config = match read_to_string(path) {
 			Ok(content) => toml::from_str(&content)?,
 			Err(err) => {
@@ -57,61 +131,366 @@ impl ConfigScheme {
 
 		Ok(config)
 	}
+
+	/// Write the config file back to disk
+	pub(crate) fn write(&self, path: &Path) -> eyre::Result<()> {
+		if let Some(parent) = path.parent() {
+			create_dir_all(parent)?;
+		}
+
+		write(path, toml::to_string_pretty(self)?)
+			.wrap_err_with(|| format!("could not write config to `{}`", path.display()))
+	}
+
+	/// The directory where the CLI stores the keys it manages, next to the config file
+	pub(crate) fn keys_dir(path: &Path) -> eyre::Result<PathBuf> {
+		let directory = path
+			.parent()
+			.wrap_err("config path has no parent directory")?
+			.join("pgpaste-keys");
+		create_dir_all(&directory)?;
+
+		// The config can be used from any working directory
+		Ok(canonicalize(directory)?)
+	}
+
+	/// The name of the selected profile, from the command line or the config default
+	pub(crate) fn profile_name<'a>(&'a self, args: &'a PGPasteArgs) -> Option<&'a str> {
+		args.profile.as_deref().or(self.default_profile.as_deref())
+	}
+
+	/// The settings of the given profile, or the top-level ones if none is given
+	pub(crate) fn profile_mut(&mut self, name: Option<&str>) -> eyre::Result<&mut ProfileScheme> {
+		match name {
+			Some(name) => self
+				.profiles
+				.get_mut(name)
+				.wrap_err_with(|| format!("no `[profiles.{name}]` table in the config")),
+			None => Ok(&mut self.base),
+		}
+	}
+
+	/// The settings of the given profile, completed with the top-level ones
+	fn resolve_profile(&self, name: Option<&str>) -> eyre::Result<ProfileScheme> {
+		let base = &self.base;
+		let Some(name) = name else {
+			return Ok(base.clone());
+		};
+
+		let profile = self
+			.profiles
+			.get(name)
+			.wrap_err_with(|| format!("no `[profiles.{name}]` table in the config"))?;
+
+		Ok(ProfileScheme {
+			server: profile.server.clone().or_else(|| base.server.clone()),
+			default_key: profile
+				.default_key
+				.clone()
+				.or_else(|| base.default_key.clone()),
+			private_keys: profile
+				.private_keys
+				.clone()
+				.or_else(|| base.private_keys.clone()),
+			public_keys: profile
+				.public_keys
+				.clone()
+				.or_else(|| base.public_keys.clone()),
+			default_visibility: profile.default_visibility.or(base.default_visibility),
+			default_lifetime: profile
+				.default_lifetime
+				.clone()
+				.or_else(|| base.default_lifetime.clone()),
+		})
+	}
+}
+
+impl ProfileScheme {
+	/// Add a private key file if it is not already listed
+	pub(crate) fn add_private_key(&mut self, path: PathBuf) {
+		push_path(self.private_keys.get_or_insert_with(Vec::new), path);
+	}
+
+	/// Add a public key file if it is not already listed
+	pub(crate) fn add_public_key(&mut self, path: PathBuf) {
+		push_path(self.public_keys.get_or_insert_with(Vec::new), path);
+	}
+}
+
+/// Add a path to a list if it is not already there
+fn push_path(paths: &mut Vec<PathBuf>, path: PathBuf) {
+	if !paths.contains(&path) {
+		paths.push(path);
+	}
 }
 
 /// The real config parsed and used in code.
 #[derive(Debug)]
 pub(crate) struct Config {
+	/// The path of the config file
+	pub(crate) path: PathBuf,
+	/// The selected profile, the top-level settings are used if unset
+	pub(crate) profile: Option<String>,
+
 	/// The pgpaste server to query
 	pub(crate) server: Url,
 
 	/// The default key to use when encrypting or signing pastes
 	pub(crate) default_key: Option<KeyHandle>,
+	/// Whether private pastes should also be encrypted to the default key
+	pub(crate) encrypt_to_self: bool,
+	/// The visibility of created pastes when `--mode` is not given
+	pub(crate) default_visibility: Option<Visibility>,
+	/// The lifetime of created pastes when no burn date is given
+	pub(crate) default_lifetime: Option<Duration>,
 	/// A set of private keys to use when encrypting or decrypting pastes
 	pub(crate) private_keys: Vec<Cert>,
 	/// A set of public keys to use when signing or verifying pastes
 	pub(crate) public_keys: Vec<Cert>,
+	/// The private keys whose secret material is held by a `gpg-agent`
+	pub(crate) agent_keys: AgentKeys,
+	/// Keeps the secret keys unlocked for the whole invocation
+	pub(crate) unlocker: KeyUnlocker,
+	/// Finds the certs of unknown recipients and signers
+	pub(crate) lookup: CertLookup,
+	/// Whether the user may be prompted for passwords or missing information
+	pub(crate) interactive: bool,
+	/// How the commands print their results
+	pub(crate) format: Format,
 }
 
 impl Config {
 	/// Initialize the config
 	pub(crate) fn new(args: &PGPasteArgs) -> eyre::Result<Self> {
-		let config = ConfigScheme::parse(args)?;
+		let path = ConfigScheme::path(args)?;
+		let config = ConfigScheme::parse(&path)?;
+		let profile_name = config.profile_name(args).map(ToOwned::to_owned);
+		let profile = config.resolve_profile(profile_name.as_deref())?;
 
-		let default_key = config
+		let default_key = profile
 			.default_key
 			.map(|key| key.parse::<KeyHandle>().to_eyre())
 			.map_or(Ok(None), |v| v.map(Some))
 			.wrap_err("not a valid key handle")?;
 
-		let private_keys = read_certs_list(config.private_keys.unwrap_or_default());
-		let public_keys = read_certs_list(config.public_keys.unwrap_or_default());
+		let default_lifetime = profile
+			.default_lifetime
+			.map(|lifetime| DurationHuman::try_from(lifetime.as_str()))
+			.transpose()
+			.wrap_err("not a valid default lifetime")?
+			.map(|lifetime| Duration::from(&lifetime));
+
+		let lookup = CertLookup::new(&config, args.offline)?;
 
-		let server = Url::parse(&args.server.clone().unwrap_or(config.server))?;
+		let mut private_keys = read_certs_list(profile.private_keys.unwrap_or_default(), false);
+		let public_keys =
+			read_certs_list(profile.public_keys.unwrap_or_default(), config.use_cert_d);
+
+		let mut agent_keys = AgentKeys::new();
+		for (fingerprint, backend) in config.keys {
+			let KeyBackend::GpgAgent { homedir } = backend else {
+				continue;
+			};
+
+			let fingerprint = fingerprint
+				.parse::<Fingerprint>()
+				.to_wrap_err("not a valid fingerprint")?;
+
+			// The agent only holds the secrets, the cert itself is a public one
+			let Some(cert) = public_keys.iter().find(|c| c.fingerprint() == fingerprint) else {
+				log::error!("no public key found for gpg-agent key {fingerprint}");
+				continue;
+			};
+
+			private_keys.retain(|c| c.fingerprint() != fingerprint);
+			private_keys.push(cert.clone());
+			agent_keys.insert(
+				fingerprint,
+				AgentKey {
+					homedir,
+					interactive: !args.no_interactive,
+				},
+			);
+		}
+
+		let server = args
+			.server
+			.clone()
+			.or(profile.server)
+			.unwrap_or_else(default_server);
+		let server = Url::parse(&server)?;
 
 		Ok(Self {
+			path,
+			profile: profile_name,
 			server,
 			default_key,
+			encrypt_to_self: config.encrypt_to_self,
+			default_visibility: profile.default_visibility,
+			default_lifetime,
 			private_keys,
 			public_keys,
+			agent_keys,
+			unlocker: KeyUnlocker::new(!args.no_interactive),
+			lookup,
+			interactive: !args.no_interactive,
+			format: args.format,
 		})
 	}
 }
 
-// TODO: read certs from folders
-/// Read a list of certificates from a list of paths
-fn read_certs_list(paths: Vec<PathBuf>) -> Vec<Cert> {
-	let mut errors = Vec::new();
+/// Read certificates from files, keyrings and directories, merging the ones found several times
+fn read_certs_list(paths: Vec<PathBuf>, cert_d: bool) -> Vec<Cert> {
+	let mut certs = CertsCollector::default();
 
-	let certs = paths
-		.into_iter()
-		.map(|path| Cert::from_file(path).to_eyre())
-		.filter_map(|r| r.map_err(|err| errors.push(err)).ok())
-		.collect();
+	for path in paths {
+		certs.read_path(&path);
+	}
 
-	for error in errors {
-		log::error!("error while reading certificate: {}", error);
+	if cert_d {
+		certs.read_cert_d();
 	}
 
-	certs
+	certs.finish()
+}
+
+/// Accumulate certificates from several sources along with the errors
+#[derive(Default)]
+struct CertsCollector {
+	/// The certs in the order they were first found
+	certs: Vec<Cert>,
+	/// The files that could not be read or parsed
+	errors: Vec<(PathBuf, eyre::Report)>,
+}
+
+impl CertsCollector {
+	/// Read a file or a whole directory
+	fn read_path(&mut self, path: &Path) {
+		if path.is_dir() {
+			self.read_dir(path);
+		} else {
+			self.read_file(path);
+		}
+	}
+
+	/// Read the certificates of the shared `pgp.cert.d` directory, if any
+	fn read_cert_d(&mut self) {
+		let Some(directory) = env::var_os("PGP_CERT_D")
+			.map(PathBuf::from)
+			.or_else(|| data_dir().map(|dir| dir.join("pgp.cert.d")))
+		else {
+			return;
+		};
+
+		// Certs are stored as `<first two hex digits>/<remaining hex digits of the
+		// fingerprint>`, other entries like `trust-root` or `_sequoia` belong to the tools
+		let is_hex = |name: &str| name.chars().all(|c| c.is_ascii_hexdigit());
+		for prefix in read_dir_sorted(&directory).unwrap_or_default() {
+			let name = prefix
+				.file_name()
+				.and_then(|name| name.to_str())
+				.unwrap_or_default();
+			if name.len() != 2 || !is_hex(name) || !prefix.is_dir() {
+				continue;
+			}
+
+			let entries = match read_dir_sorted(&prefix) {
+				Ok(entries) => entries,
+				Err(error) => {
+					self.errors.push((prefix, error.into()));
+					continue;
+				}
+			};
+
+			for path in entries {
+				let is_cert = path
+					.file_name()
+					.and_then(|name| name.to_str())
+					.is_some_and(|name| matches!(name.len(), 38 | 62) && is_hex(name));
+
+				if is_cert {
+					self.read_file(&path);
+				}
+			}
+		}
+	}
+
+	/// Read every cert of a file, it may be a keyring
+	fn read_file(&mut self, path: &Path) {
+		let parser = match CertParser::from_file(path) {
+			Ok(parser) => parser,
+			Err(error) => {
+				return self
+					.errors
+					.push((path.into(), eyre::eyre!(Box::new(error))));
+			}
+		};
+
+		for cert in parser {
+			match cert {
+				Ok(cert) => self.add(path, cert),
+				Err(error) => self
+					.errors
+					.push((path.into(), eyre::eyre!(Box::new(error)))),
+			}
+		}
+	}
+
+	/// Read every file of a directory and its subdirectories
+	fn read_dir(&mut self, directory: &Path) {
+		let entries = match read_dir_sorted(directory) {
+			Ok(entries) => entries,
+			Err(error) => return self.errors.push((directory.into(), error.into())),
+		};
+
+		for path in entries {
+			self.read_path(&path);
+		}
+	}
+
+	/// Add a cert, merging it with a previous version of it
+	fn add(&mut self, path: &Path, cert: Cert) {
+		let Some(existing) = self
+			.certs
+			.iter_mut()
+			.find(|c| c.fingerprint() == cert.fingerprint())
+		else {
+			return self.certs.push(cert);
+		};
+
+		match existing.clone().merge_public_and_secret(cert) {
+			Ok(merged) => *existing = merged,
+			Err(error) => self
+				.errors
+				.push((path.into(), eyre::eyre!(Box::new(error)))),
+		}
+	}
+
+	/// Report all the errors at once and return the certs
+	fn finish(self) -> Vec<Cert> {
+		if !self.errors.is_empty() {
+			let summary = self
+				.errors
+				.iter()
+				.map(|(path, error)| format!("\n  `{}`: {error}", path.display()))
+				.collect::<String>();
+
+			log::error!(
+				"{} certificate source(s) could not be read:{summary}",
+				self.errors.len()
+			);
+		}
+
+		self.certs
+	}
+}
+
+/// The entries of a directory in a stable order
+pub(crate) fn read_dir_sorted(directory: &Path) -> io::Result<Vec<PathBuf>> {
+	let mut entries = read_dir(directory)?
+		.map(|entry| entry.map(|entry| entry.path()))
+		.collect::<io::Result<Vec<_>>>()?;
+	entries.sort();
+
+	Ok(entries)
 }
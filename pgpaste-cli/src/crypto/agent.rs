@@ -0,0 +1,173 @@
+//! Secret keys held by a `gpg-agent`
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Context as _;
+use sequoia_gpg_agent::{KeyPair, gnupg::Context};
+use sequoia_openpgp::{
+	Cert, Fingerprint,
+	packet::{
+		Key,
+		key::{PublicParts, UnspecifiedRole},
+	},
+};
+
+use super::POLICY;
+
+/// The keys whose secret material is held by a `gpg-agent`, by primary fingerprint
+pub(crate) type AgentKeys = HashMap<Fingerprint, AgentKey>;
+
+/// A key whose secret material never leaves the `gpg-agent`
+#[derive(Debug, Clone)]
+pub(crate) struct AgentKey {
+	/// The GnuPG home directory of the agent, the default one if unset
+	pub(crate) homedir: Option<PathBuf>,
+	/// Whether the agent may open pinentry, otherwise only cached or unprotected keys work
+	pub(crate) interactive: bool,
+}
+
+impl AgentKey {
+	/// A keypair that signs and decrypts through the agent, pinentry asks for the passphrase
+	/// unless the invocation is not interactive
+	pub(crate) fn keypair(
+		&self,
+		cert: Option<&Cert>,
+		key: &Key<PublicParts, UnspecifiedRole>,
+	) -> sequoia_openpgp::Result<KeyPair> {
+		let context = match &self.homedir {
+			Some(homedir) => Context::with_homedir(homedir),
+			None => Context::new(),
+		}
+		.context("could not find the GnuPG home directory")?;
+
+		// The agent is started on demand like GnuPG does
+		context
+			.start("gpg-agent")
+			.context("could not start gpg-agent")?;
+
+		let mut keypair = KeyPair::new_for_gnupg_context(&context, key)?;
+		if !self.interactive {
+			// Fail right away instead of waiting on a pinentry nobody answers
+			keypair = keypair.suppress_pinentry();
+		}
+
+		// Give pinentry some context about the key
+		Ok(match cert.map(|cert| cert.with_policy(POLICY, None)) {
+			Some(Ok(valid_cert)) => keypair.with_cert(&valid_cert),
+			_ => keypair,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		io::Write,
+		process::{Command, Stdio},
+	};
+
+	use anyhow::Context as _;
+	use sequoia_gpg_agent::gnupg::Context;
+	use sequoia_openpgp::{
+		Cert,
+		cert::CertBuilder,
+		crypto::{Decryptor, SessionKey, Signer},
+		packet::pkesk::PKESK3,
+		serialize::SerializeInto,
+		types::{HashAlgorithm, SymmetricAlgorithm},
+	};
+	use tempfile::TempDir;
+
+	use super::{AgentKey, POLICY};
+
+	/// A throwaway `GNUPGHOME` whose agent is stopped with it
+	struct GnupgHome(TempDir);
+
+	impl GnupgHome {
+		/// Create the home directory and import the secret keys of the cert with `gpg`
+		fn with_cert(cert: &Cert) -> sequoia_openpgp::Result<Self> {
+			let home = Self(tempfile::tempdir()?);
+
+			let mut gpg = Command::new("gpg")
+				.arg("--homedir")
+				.arg(home.0.path())
+				.args(["--batch", "--import"])
+				.stdin(Stdio::piped())
+				.stdout(Stdio::null())
+				.stderr(Stdio::null())
+				.spawn()?;
+			gpg.stdin
+				.take()
+				.context("gpg has no stdin")?
+				.write_all(&cert.as_tsk().armored().to_vec()?)?;
+			anyhow::ensure!(gpg.wait()?.success(), "gpg could not import the key");
+
+			Ok(home)
+		}
+
+		/// An agent key of this home that never opens pinentry
+		fn agent_key(&self) -> AgentKey {
+			AgentKey {
+				homedir: Some(self.0.path().to_path_buf()),
+				interactive: false,
+			}
+		}
+	}
+
+	impl Drop for GnupgHome {
+		fn drop(&mut self) {
+			if let Ok(context) = Context::with_homedir(self.0.path()) {
+				let _ = context.stop_all();
+			}
+		}
+	}
+
+	#[test]
+	#[ignore = "needs gpg"]
+	fn sign_and_decrypt_through_gpg_agent() -> sequoia_openpgp::Result<()> {
+		let (cert, _) = CertBuilder::new()
+			.add_userid("agent <agent@example.org>")
+			.add_signing_subkey()
+			.add_transport_encryption_subkey()
+			.generate()?;
+		let home = GnupgHome::with_cert(&cert)?;
+		let agent_key = home.agent_key();
+		let valid_cert = cert.with_policy(POLICY, None)?;
+
+		let signing_key = valid_cert
+			.keys()
+			.for_signing()
+			.next()
+			.context("no signing key")?
+			.key()
+			.clone()
+			.role_into_unspecified();
+		let mut signer = agent_key.keypair(Some(&cert), &signing_key)?;
+
+		let digest = [0x42; 32];
+		let signature = signer.sign(HashAlgorithm::SHA256, &digest)?;
+		signing_key.verify(&signature, HashAlgorithm::SHA256, &digest)?;
+
+		let encryption_key = valid_cert
+			.keys()
+			.for_transport_encryption()
+			.next()
+			.context("no encryption key")?
+			.key()
+			.clone()
+			.role_into_unspecified();
+		let mut decryptor = agent_key.keypair(None, &encryption_key)?;
+
+		let session_key = SessionKey::new(32);
+		let pkesk =
+			PKESK3::for_recipient(SymmetricAlgorithm::AES256, &session_key, &encryption_key)?;
+		let (algo, decrypted) = pkesk
+			.decrypt(&mut decryptor as &mut dyn Decryptor, None)
+			.context("gpg-agent could not decrypt the session key")?;
+
+		assert_eq!(algo, SymmetricAlgorithm::AES256);
+		assert_eq!(&decrypted[..], &session_key[..]);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,552 @@
+//! Find certs on keyservers and Web Key Directories, keeping them in a local cache.
+
+use std::{
+	fs::{File, create_dir_all, write},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use dirs::cache_dir;
+use duration_human::DurationHuman;
+use eyre::{Context, ContextCompat};
+use sequoia_net::{KeyServer, Policy, wkd};
+use sequoia_openpgp::{Cert, KeyHandle, parse::Parse, serialize::SerializeInto};
+
+use crate::{
+	ToEyreError, block_on,
+	config::{ConfigScheme, read_dir_sorted},
+};
+
+/// The keyserver used when none is configured
+const DEFAULT_KEYSERVER: &str = "hkps://keys.openpgp.org";
+
+/// How long a cached cert is used before being fetched again when no interval is configured
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Looks certs up in the cache first, then on the configured keyservers and WKD.
+#[derive(Debug)]
+pub(crate) struct CertLookup {
+	/// The keyservers to query, in order
+	keyservers: Vec<String>,
+	/// Whether to query the Web Key Directory of email addresses
+	wkd: bool,
+	/// Never touch the network, only the cache is used
+	offline: bool,
+	/// The directory where fetched certs are kept
+	cache: PathBuf,
+	/// How long a cached cert is used before being fetched again
+	refresh_interval: Duration,
+}
+
+impl CertLookup {
+	/// Build the lookup settings from the config, `offline` comes from the command line
+	pub(crate) fn new(config: &ConfigScheme, offline: bool) -> eyre::Result<Self> {
+		let cache = match &config.cert_cache {
+			Some(path) => path.clone(),
+			None => cache_dir()
+				.wrap_err("Could not find cache directory")?
+				.join("pgpaste")
+				.join("certs"),
+		};
+
+		let refresh_interval = config
+			.cert_cache_refresh
+			.as_deref()
+			.map(DurationHuman::try_from)
+			.transpose()
+			.wrap_err("not a valid cert cache refresh interval")?
+			.map_or(DEFAULT_REFRESH_INTERVAL, |interval| {
+				Duration::from(&interval)
+			});
+
+		Ok(Self {
+			keyservers: config
+				.keyservers
+				.clone()
+				.unwrap_or_else(|| vec![DEFAULT_KEYSERVER.into()]),
+			wkd: config.use_wkd.unwrap_or(true),
+			offline: offline || config.offline,
+			cache,
+			refresh_interval,
+		})
+	}
+
+	/// Returns the cert owning the given key handle, the handle may be the one of a subkey
+	///
+	/// A stale cached cert is still used when it cannot be fetched again.
+	pub(crate) fn get(&self, handle: &KeyHandle) -> eyre::Result<Cert> {
+		let cached = self.cached(handle);
+		if let Some((cert, fresh)) = &cached
+			&& (*fresh || self.offline)
+		{
+			log::debug!("using the cached cert {}", cert.fingerprint());
+			return Ok(cert.clone());
+		}
+
+		match self.fetch(handle, &[]) {
+			Ok(cert) => self.store(cert),
+			Err(err) => match cached {
+				Some((cert, _)) => {
+					log::warn!(
+						"Could not refresh the cached cert {}, using it anyway: {err}",
+						cert.fingerprint()
+					);
+					Ok(cert)
+				}
+				None => Err(err),
+			},
+		}
+	}
+
+	/// Returns the certs with the given email address from WKD and the keyservers
+	///
+	/// Keyservers do not always verify the addresses they publish, so their results are
+	/// only trusted when WKD confirms them or when a single cert claims the address.
+	pub(crate) fn search(&self, email: &str) -> eyre::Result<Vec<Cert>> {
+		if self.offline {
+			let certs = self
+				.cached_certs()
+				.into_iter()
+				.map(|(cert, _)| cert)
+				.filter(|cert| emails(cert).iter().any(|e| e == email))
+				.collect::<Vec<_>>();
+
+			if certs.is_empty() {
+				eyre::bail!("no cached cert for {email} and cannot look it up in offline mode");
+			}
+			return single(email, certs, "in the cert cache");
+		}
+
+		let mut from_wkd = Vec::new();
+		let mut from_keyservers = Vec::new();
+		let mut errors = Vec::new();
+		block_on(async {
+			if self.wkd {
+				match wkd::get(email).await {
+					Ok(certs) => from_wkd.extend(certs),
+					Err(err) => errors.push(format!("WKD: {err}")),
+				}
+			}
+
+			for uri in &self.keyservers {
+				match keyserver(uri) {
+					Ok(mut keyserver) => match keyserver.search(email).await {
+						Ok(certs) => from_keyservers.extend(certs),
+						Err(err) => errors.push(format!("{uri}: {err}")),
+					},
+					Err(err) => errors.push(format!("{uri}: {err}")),
+				}
+			}
+		});
+
+		let from_wkd = merge_all(from_wkd)?;
+		let from_keyservers = merge_all(from_keyservers)?;
+
+		let certs = if from_wkd.is_empty() {
+			if from_keyservers.is_empty() {
+				eyre::bail!("no cert found for {email}: {}", errors.join(", "));
+			}
+			single(email, from_keyservers, "on the keyservers")?
+		} else {
+			// The keyservers may carry updates of the published certs, but nothing else
+			let (confirmed, unconfirmed) =
+				from_keyservers.into_iter().partition::<Vec<_>, _>(|cert| {
+					from_wkd
+						.iter()
+						.any(|published| published.fingerprint() == cert.fingerprint())
+				});
+			for cert in unconfirmed {
+				log::warn!(
+					"Ignoring {} from the keyservers, the WKD of {email} does not publish it",
+					cert.fingerprint()
+				);
+			}
+
+			merge_all(from_wkd.into_iter().chain(confirmed).collect())?
+		};
+
+		certs.into_iter().map(|cert| self.store(cert)).collect()
+	}
+
+	/// Fetches the given cert again, whatever the age of the cached copy, and
+	/// returns it merged with the update
+	pub(crate) fn refresh(&self, cert: &Cert) -> eyre::Result<Cert> {
+		let update = self.fetch(&cert.key_handle(), &emails(cert))?;
+		let cert = cert.clone().merge_public(update).to_eyre()?;
+		self.store(cert.clone())?;
+
+		Ok(cert)
+	}
+
+	/// Fetches a cert from the keyservers and from the WKD of the given emails
+	fn fetch(&self, handle: &KeyHandle, emails: &[String]) -> eyre::Result<Cert> {
+		if self.offline {
+			eyre::bail!("cannot look {handle} up on the network in offline mode");
+		}
+
+		let mut found = Vec::new();
+		let mut errors = Vec::new();
+		block_on(async {
+			for uri in &self.keyservers {
+				match keyserver(uri) {
+					Ok(mut keyserver) => match keyserver.get(handle.clone()).await {
+						Ok(cert) => found.push(cert),
+						Err(err) => errors.push(format!("{uri}: {err}")),
+					},
+					Err(err) => errors.push(format!("{uri}: {err}")),
+				}
+			}
+
+			if !self.wkd {
+				return;
+			}
+
+			// The directory may publish other certs for the same address
+			for email in emails {
+				match wkd::get(email).await {
+					Ok(certs) => found.extend(certs.into_iter().filter(|cert| owns(cert, handle))),
+					Err(err) => errors.push(format!("WKD of {email}: {err}")),
+				}
+			}
+		});
+
+		if found.is_empty() {
+			if errors.is_empty() {
+				eyre::bail!("no keyserver is configured to look {handle} up");
+			}
+			eyre::bail!("could not fetch {handle}: {}", errors.join(", "));
+		}
+
+		let mut certs = merge_all(found)?;
+		if certs.len() > 1 {
+			eyre::bail!("several certs were found for {handle}");
+		}
+
+		Ok(certs.remove(0))
+	}
+
+	/// The cached cert owning the given handle, and whether it is still fresh
+	fn cached(&self, handle: &KeyHandle) -> Option<(Cert, bool)> {
+		self.cached_certs()
+			.into_iter()
+			.find(|(cert, _)| owns(cert, handle))
+			.map(|(cert, modified)| {
+				let age = modified.elapsed().unwrap_or_default();
+				(cert, age < self.refresh_interval)
+			})
+	}
+
+	/// Every cached cert along with the last time it was fetched
+	fn cached_certs(&self) -> Vec<(Cert, SystemTime)> {
+		let Ok(entries) = read_dir_sorted(&self.cache) else {
+			return Vec::new();
+		};
+
+		entries
+			.iter()
+			.filter_map(|path| match read_cached(path) {
+				Ok(entry) => Some(entry),
+				Err(err) => {
+					log::warn!("Ignoring the cached cert `{}`: {err}", path.display());
+					None
+				}
+			})
+			.collect()
+	}
+
+	/// Write a fetched cert in the cache, merged with the cached copy
+	fn store(&self, cert: Cert) -> eyre::Result<Cert> {
+		create_dir_all(&self.cache)?;
+		let path = self.cache.join(format!("{}.pgp", cert.fingerprint()));
+
+		let cert = match Cert::from_file(&path) {
+			Ok(cached) if cached.fingerprint() == cert.fingerprint() => {
+				cached.merge_public(cert).to_eyre()?
+			}
+			_ => cert.strip_secret_key_material(),
+		};
+
+		// Rewriting the file also marks it as fresh
+		write(&path, cert.armored().to_vec().to_eyre()?)?;
+
+		Ok(cert)
+	}
+}
+
+/// Read a cached cert and its modification time
+fn read_cached(path: &Path) -> eyre::Result<(Cert, SystemTime)> {
+	let modified = File::open(path)?.metadata()?.modified()?;
+	let cert = Cert::from_file(path).to_eyre()?;
+
+	Ok((cert, modified))
+}
+
+/// Connect to a keyserver, plain `hkp://` ones are only meant for local setups
+fn keyserver(uri: &str) -> eyre::Result<KeyServer> {
+	let policy = if uri.starts_with("hkp://") {
+		Policy::Insecure
+	} else {
+		Policy::Encrypted
+	};
+
+	KeyServer::new(policy, uri).to_eyre()
+}
+
+/// Whether one of the keys of the cert has the given handle, signatures and
+/// encryption are often made by a subkey rather than the primary key
+pub(crate) fn owns(cert: &Cert, handle: &KeyHandle) -> bool {
+	cert.keys().any(|ka| handle.aliases(ka.key().key_handle()))
+}
+
+/// The email addresses of the user IDs of a cert
+fn emails(cert: &Cert) -> Vec<String> {
+	cert.userids()
+		.filter_map(|uid| uid.userid().email2().ok().flatten().map(ToOwned::to_owned))
+		.collect()
+}
+
+/// The certs claiming an email address if there is only one, the user has to choose otherwise
+fn single(email: &str, certs: Vec<Cert>, source: &str) -> eyre::Result<Vec<Cert>> {
+	if certs.len() > 1 {
+		let fingerprints = certs
+			.iter()
+			.map(|cert| cert.fingerprint().to_hex())
+			.collect::<Vec<_>>();
+		eyre::bail!(
+			"several certs claim {email} {source}, import the expected one by its fingerprint: {}",
+			fingerprints.join(", ")
+		);
+	}
+
+	Ok(certs)
+}
+
+/// Merge the certs found several times, e.g. on different keyservers
+fn merge_all(certs: Vec<Cert>) -> eyre::Result<Vec<Cert>> {
+	let mut merged: Vec<Cert> = Vec::new();
+	for cert in certs {
+		match merged
+			.iter_mut()
+			.find(|c| c.fingerprint() == cert.fingerprint())
+		{
+			Some(existing) => *existing = existing.clone().merge_public(cert).to_eyre()?,
+			None => merged.push(cert),
+		}
+	}
+
+	Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		fs::File,
+		io::{BufRead, BufReader, Write},
+		net::TcpListener,
+		sync::{
+			Arc,
+			atomic::{AtomicUsize, Ordering},
+		},
+		thread,
+		time::{Duration, SystemTime},
+	};
+
+	use sequoia_openpgp::{
+		Cert,
+		armor::{Kind, Writer},
+		cert::CertBuilder,
+		serialize::Serialize,
+	};
+	use tempfile::TempDir;
+
+	use super::CertLookup;
+	use crate::ToEyreError;
+
+	/// A local HKP keyserver serving fixture certs and counting its requests
+	struct HkpStandIn {
+		/// The `hkp://` URI of the server
+		uri: String,
+		/// How many requests were answered
+		requests: Arc<AtomicUsize>,
+	}
+
+	impl HkpStandIn {
+		/// Serve the given certs on a random local port
+		fn spawn(certs: Vec<Cert>) -> eyre::Result<Self> {
+			let listener = TcpListener::bind("127.0.0.1:0")?;
+			let uri = format!("hkp://{}", listener.local_addr()?);
+			let requests = Arc::new(AtomicUsize::new(0));
+
+			let counter = Arc::clone(&requests);
+			thread::spawn(move || {
+				for stream in listener.incoming().flatten() {
+					counter.fetch_add(1, Ordering::SeqCst);
+					let _ = answer(stream, &certs);
+				}
+			});
+
+			Ok(Self { uri, requests })
+		}
+
+		/// How many requests were answered so far
+		fn requests(&self) -> usize {
+			self.requests.load(Ordering::SeqCst)
+		}
+	}
+
+	/// Answer a `/pks/lookup?op=get` request by fingerprint, key ID or email address
+	fn answer(mut stream: std::net::TcpStream, certs: &[Cert]) -> sequoia_openpgp::Result<()> {
+		let mut reader = BufReader::new(stream.try_clone()?);
+		let mut request_line = String::new();
+		reader.read_line(&mut request_line)?;
+		loop {
+			let mut header = String::new();
+			if reader.read_line(&mut header)? <= 2 {
+				break;
+			}
+		}
+
+		let search = request_line
+			.split_whitespace()
+			.nth(1)
+			.and_then(|path| path.split("search=").nth(1))
+			.unwrap_or_default()
+			.replace("%40", "@");
+
+		let matching = certs
+			.iter()
+			.filter(|cert| match search.strip_prefix("0x") {
+				Some(hex) => cert
+					.keys()
+					.any(|ka| ka.key().fingerprint().to_hex().ends_with(hex)),
+				None => cert
+					.userids()
+					.any(|uid| uid.userid().to_string().contains(&search)),
+			})
+			.collect::<Vec<_>>();
+
+		let mut body = Vec::new();
+		let status = if matching.is_empty() {
+			"404 Not Found"
+		} else {
+			let mut writer = Writer::new(&mut body, Kind::PublicKey)?;
+			for cert in matching {
+				cert.serialize(&mut writer)?;
+			}
+			writer.finalize()?;
+			"200 OK"
+		};
+
+		write!(
+			stream,
+			"HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+			body.len()
+		)?;
+		stream.write_all(&body)?;
+
+		Ok(())
+	}
+
+	/// A fresh cert with the given user ID
+	fn cert(userid: &str) -> eyre::Result<Cert> {
+		Ok(CertBuilder::new()
+			.add_userid(userid)
+			.add_signing_subkey()
+			.generate()
+			.to_eyre()?
+			.0)
+	}
+
+	/// A lookup on the given keyserver, with a cache in a temporary directory
+	fn lookup(keyserver: &HkpStandIn, cache: &TempDir, offline: bool) -> CertLookup {
+		CertLookup {
+			keyservers: vec![keyserver.uri.clone()],
+			wkd: false,
+			offline,
+			cache: cache.path().to_path_buf(),
+			refresh_interval: Duration::from_secs(60 * 60),
+		}
+	}
+
+	#[test]
+	fn get_fetches_caches_and_refreshes() -> eyre::Result<()> {
+		let alice = cert("alice <alice@example.org>")?;
+		let keyserver = HkpStandIn::spawn(vec![alice.clone()])?;
+		let cache = tempfile::tempdir()?;
+		let lookup = lookup(&keyserver, &cache, false);
+
+		// Signatures are made by the subkey
+		let subkey = alice
+			.keys()
+			.subkeys()
+			.next()
+			.map(|ka| ka.key().key_handle());
+		let subkey = subkey.ok_or_else(|| eyre::eyre!("no subkey"))?;
+
+		let fetched = lookup.get(&subkey)?;
+		assert_eq!(fetched.fingerprint(), alice.fingerprint());
+		assert_eq!(keyserver.requests(), 1);
+
+		let cached = lookup.get(&alice.key_handle())?;
+		assert_eq!(cached.fingerprint(), alice.fingerprint());
+		assert_eq!(keyserver.requests(), 1);
+
+		let path = cache.path().join(format!("{}.pgp", alice.fingerprint()));
+		File::options()
+			.write(true)
+			.open(&path)?
+			.set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))?;
+
+		lookup.get(&alice.key_handle())?;
+		assert_eq!(keyserver.requests(), 2);
+
+		Ok(())
+	}
+
+	#[test]
+	fn offline_never_connects() -> eyre::Result<()> {
+		let alice = cert("alice <alice@example.org>")?;
+		let bob = cert("bob <bob@example.org>")?;
+		let keyserver = HkpStandIn::spawn(vec![alice.clone(), bob.clone()])?;
+		let cache = tempfile::tempdir()?;
+
+		lookup(&keyserver, &cache, false).get(&alice.key_handle())?;
+		assert_eq!(keyserver.requests(), 1);
+
+		let path = cache.path().join(format!("{}.pgp", alice.fingerprint()));
+		File::options()
+			.write(true)
+			.open(&path)?
+			.set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))?;
+
+		let offline = lookup(&keyserver, &cache, true);
+		assert_eq!(
+			offline.get(&alice.key_handle())?.fingerprint(),
+			alice.fingerprint()
+		);
+		assert!(offline.get(&bob.key_handle()).is_err());
+		assert!(offline.search("bob@example.org").is_err());
+		assert!(offline.refresh(&alice).is_err());
+		assert_eq!(keyserver.requests(), 1);
+
+		Ok(())
+	}
+
+	#[test]
+	fn search_refuses_ambiguous_keyserver_results() -> eyre::Result<()> {
+		let alice = cert("alice <alice@example.org>")?;
+		let impostor = cert("alice <alice@example.org>")?;
+		let bob = cert("bob <bob@example.org>")?;
+		let keyserver = HkpStandIn::spawn(vec![alice, impostor, bob.clone()])?;
+		let cache = tempfile::tempdir()?;
+		let lookup = lookup(&keyserver, &cache, false);
+
+		assert!(lookup.search("alice@example.org").is_err());
+
+		let found = lookup.search("bob@example.org")?;
+		assert_eq!(found.len(), 1);
+		assert_eq!(found[0].fingerprint(), bob.fingerprint());
+
+		Ok(())
+	}
+}
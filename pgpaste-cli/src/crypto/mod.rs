@@ -1,12 +1,14 @@
 //! Cryptographic functions to sign/verify or encrypt/decrypt a message.
 
-use sequoia_openpgp::policy::StandardPolicy;
-
+mod agent;
+mod lookup;
 mod receive;
 mod send;
+mod unlock;
 
-pub(crate) use receive::{ReceiveHelper, decrypt, verify};
+pub(crate) use agent::{AgentKey, AgentKeys};
+pub(crate) use lookup::{CertLookup, owns};
+pub(crate) use pgpaste_client::{Received, crypto::POLICY};
+pub(crate) use receive::{ReceiveHelper, open};
 pub(crate) use send::{SendHelper, encrypt, protect, sign};
-
-/// Default policy used for certificate verification
-const POLICY: &StandardPolicy = &StandardPolicy::new();
+pub(crate) use unlock::KeyUnlocker;
@@ -1,70 +1,73 @@
 //! Decrypting and verifying messages
 
-use std::{collections::HashMap, io};
+use std::collections::HashMap;
 
+use pgpaste_api_types::Paste;
+use pgpaste_client::PasteDecryptor;
 use sequoia_openpgp::{
 	Cert, Fingerprint, KeyHandle, KeyID,
-	crypto::{self, Decryptor, SessionKey},
+	crypto::{self, Decryptor, Password, SessionKey},
 	packet::{
-		self, Key,
-		key::{SecretParts, UnspecifiedRole},
-	},
-	parse::{
-		Parse,
-		stream::{
-			DecryptionHelper, DecryptorBuilder, MessageLayer, MessageStructure, VerificationHelper,
-			VerifierBuilder,
-		},
+		Key, PKESK, SKESK,
+		key::{PublicParts, SecretParts, UnspecifiedRole},
 	},
 	types::SymmetricAlgorithm,
 };
 
-use super::POLICY;
-use crate::ToEyreError;
-
-/// Verify the given message with the given helper.
-pub(crate) fn verify(message: &[u8], helper: ReceiveHelper) -> eyre::Result<Vec<u8>> {
-	let mut decryptor = VerifierBuilder::from_bytes(&message)
-		.to_eyre()?
-		.with_policy(POLICY, None, helper)
-		.to_eyre()?;
-
-	let mut out = Vec::new();
-	io::copy(&mut decryptor, &mut out)?;
+use super::{AgentKey, AgentKeys, CertLookup, KeyUnlocker, POLICY, Received, owns};
+use crate::{ToEyreError, prompt};
 
-	Ok(out)
-}
+/// The callback that checks a session key against the message
+type DecryptFn<'a> = dyn FnMut(SymmetricAlgorithm, &SessionKey) -> bool + 'a;
 
-/// Decrypt the given message with the given helper.
-pub(crate) fn decrypt(ciphertext: &[u8], helper: ReceiveHelper) -> eyre::Result<Vec<u8>> {
-	let mut decryptor = DecryptorBuilder::from_bytes(ciphertext)
-		.to_eyre()?
-		.with_policy(POLICY, None, helper)
-		.to_eyre()?;
+/// Verify or decrypt the inner message of a paste with the given helper.
+pub(crate) fn open(paste: &Paste, helper: &mut ReceiveHelper) -> eyre::Result<Received> {
+	let received = pgpaste_client::crypto::open(paste, helper)?;
 
-	let mut out = Vec::new();
-	io::copy(&mut decryptor, &mut out)?;
+	if let Some(fingerprint) = &received.decrypted_with {
+		let keyid = KeyID::from(fingerprint);
+		match helper.hints.get(&keyid) {
+			Some(identity) => log::info!("Decrypted with key {keyid} of {identity}"),
+			None => log::info!("Decrypted with key {keyid}"),
+		}
+	}
 
-	Ok(out)
+	Ok(received)
 }
 
-/// This helper provides secrets for the decryption, fetches public
-/// keys for the signature verification and implements the
-/// verification policy.
+/// This helper provides secrets for the decryption and public keys
+/// for the signature verification, fetching the ones of unknown signers.
 #[derive(Debug)]
 pub(crate) struct ReceiveHelper<'a> {
 	/// The certs used for decrypting
 	secrets: HashMap<KeyID, Key<SecretParts, UnspecifiedRole>>,
+	/// The keys used for decrypting whose secret material is held by a `gpg-agent`
+	agent_keys: HashMap<KeyID, (Key<PublicParts, UnspecifiedRole>, AgentKey)>,
 	/// The certs used for verification
 	public_certs: &'a [Cert],
+	/// The certs of unknown signers fetched from the keyservers
+	fetched_certs: Vec<Cert>,
+	/// Keeps the secret keys unlocked for the whole invocation
+	unlocker: &'a KeyUnlocker,
+	/// Finds the certs of unknown signers
+	lookup: &'a CertLookup,
 	/// Hints used when prompting the user to decrypt their key.
 	hints: HashMap<KeyID, String>,
+	/// The password of a protected message, prompted for if unset
+	password: Option<Password>,
 }
 
 impl<'a> ReceiveHelper<'a> {
 	/// Creates a Helper for the given Certs with appropriate secrets.
-	pub(crate) fn new(private_certs: &[Cert], public_certs: &'a [Cert]) -> eyre::Result<Self> {
+	pub(crate) fn new(
+		private_certs: &[Cert],
+		public_certs: &'a [Cert],
+		agent_keys: &AgentKeys,
+		unlocker: &'a KeyUnlocker,
+		lookup: &'a CertLookup,
+	) -> eyre::Result<Self> {
 		let mut secrets: HashMap<KeyID, Key<SecretParts, UnspecifiedRole>> = HashMap::new();
+		let mut agent_secrets = HashMap::new();
 		let mut hints = HashMap::new();
 
 		for private_cert in private_certs {
@@ -80,6 +83,24 @@ impl<'a> ReceiveHelper<'a> {
 			log::debug!("found identity: {identity}");
 			hints.insert(private_cert.keyid(), identity.clone());
 
+			if let Some(agent_key) = agent_keys.get(&private_cert.fingerprint()) {
+				for ka in private_cert
+					.keys()
+					.with_policy(POLICY, None)
+					.for_transport_encryption()
+					.for_storage_encryption()
+				{
+					log::debug!("found key held by gpg-agent: {}", ka.key());
+					hints.insert(ka.key().keyid(), identity.clone());
+					agent_secrets.insert(
+						ka.key().keyid(),
+						(ka.key().role_as_unspecified().clone(), agent_key.clone()),
+					);
+				}
+
+				continue;
+			}
+
 			for ka in private_cert
 				.keys()
 				.with_policy(POLICY, None)
@@ -99,21 +120,29 @@ impl<'a> ReceiveHelper<'a> {
 
 		Ok(Self {
 			secrets,
+			agent_keys: agent_secrets,
 			public_certs,
+			fetched_certs: Vec::new(),
+			unlocker,
+			lookup,
 			hints,
+			password: None,
 		})
 	}
 
+	/// Use this password for protected messages instead of prompting
+	pub(crate) fn with_password(mut self, password: Option<Password>) -> Self {
+		self.password = password;
+		self
+	}
+
 	/// Returns the identity of the given key if it is able to decrypt it
-	fn try_decrypt<D>(
-		pkesk: &packet::PKESK,
+	fn try_decrypt(
+		pkesk: &PKESK,
 		sym_algo: Option<SymmetricAlgorithm>,
 		mut keypair: Box<dyn crypto::Decryptor>,
-		decrypt: &mut D,
-	) -> Option<Fingerprint>
-	where
-		D: FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
-	{
+		decrypt: &mut DecryptFn<'_>,
+	) -> Option<Fingerprint> {
 		pkesk
 			.decrypt(&mut *keypair, sym_algo)
 			.and_then(
@@ -125,62 +154,130 @@ impl<'a> ReceiveHelper<'a> {
 	}
 }
 
-impl VerificationHelper for ReceiveHelper<'_> {
-	fn get_certs(&mut self, ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
-		let concerned_certs = self
-			.public_certs
-			.iter()
-			.filter(|cert| {
-				ids.iter()
-					.any(|handle| handle == &cert.fingerprint().into())
-			})
-			.cloned()
-			.collect::<Vec<_>>();
+impl ReceiveHelper<'_> {
+	/// Tries all our secret keys on the given wildcard recipients, unlocked
+	/// keys first, then the encrypted ones with a single prompt per key.
+	fn try_decrypt_wildcards(
+		&mut self,
+		wildcards: &[&PKESK],
+		sym_algo: Option<SymmetricAlgorithm>,
+		decrypt: &mut DecryptFn<'_>,
+	) -> sequoia_openpgp::Result<Option<Fingerprint>> {
+		let mut encrypted = Vec::new();
+
+		for (keyid, key) in &self.secrets {
+			if !wildcards
+				.iter()
+				.any(|pkesk| pkesk.pk_algo() == key.pk_algo())
+			{
+				continue;
+			}
 
-		Ok(concerned_certs)
-	}
+			let Some(keypair) = self.unlocker.unlocked(key)? else {
+				encrypted.push(keyid);
+				continue;
+			};
 
-	// TODO: implement message structure verification policy
-	fn check(&mut self, structure: MessageStructure) -> sequoia_openpgp::Result<()> {
-		for layer in structure.iter() {
-			match layer {
-				MessageLayer::Compression { .. } | MessageLayer::Encryption { .. } => {}
-				MessageLayer::SignatureGroup { results } => {
-					for result in results {
-						if let Err(e) = result {
-							anyhow::bail!("signature verification failed: {}", e)
-						}
+			for pkesk in wildcards {
+				let keypair = Box::new(keypair.clone());
+				if let Some(fp) = Self::try_decrypt(pkesk, sym_algo, keypair, decrypt) {
+					return Ok(Some(fp));
+				}
+			}
+		}
+
+		for (key, agent_key) in self.agent_keys.values() {
+			for pkesk in wildcards
+				.iter()
+				.filter(|pkesk| pkesk.pk_algo() == key.pk_algo())
+			{
+				let keypair = match agent_key.keypair(None, key) {
+					Ok(keypair) => Box::new(keypair),
+					Err(error) => {
+						log::warn!("Could not use the gpg-agent key {}: {error}", key.keyid());
+						break;
 					}
+				};
+				if let Some(fp) = Self::try_decrypt(pkesk, sym_algo, keypair, decrypt) {
+					return Ok(Some(fp));
 				}
 			}
 		}
 
-		Ok(())
+		for keyid in encrypted {
+			let hint = format!(
+				"{} on hidden recipients",
+				self.hints
+					.get(keyid)
+					.expect("keyid come from the same source as hints")
+			);
+
+			// A wrong password skips the key instead of asking again
+			let Some(keypair) = self.unlocker.try_unlock(&self.secrets[keyid], &hint)? else {
+				continue;
+			};
+
+			for pkesk in wildcards {
+				let keypair = Box::new(keypair.clone());
+				if let Some(fp) = Self::try_decrypt(pkesk, sym_algo, keypair, decrypt) {
+					return Ok(Some(fp));
+				}
+			}
+		}
+
+		Ok(None)
 	}
 }
 
 #[allow(clippy::similar_names)]
-impl DecryptionHelper for ReceiveHelper<'_> {
-	fn decrypt<D>(
+impl PasteDecryptor for ReceiveHelper<'_> {
+	fn certs(&mut self, ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+		for handle in ids {
+			let known = self
+				.public_certs
+				.iter()
+				.chain(&self.fetched_certs)
+				.any(|cert| owns(cert, handle));
+			if known {
+				continue;
+			}
+
+			match self.lookup.get(handle) {
+				Ok(cert) => {
+					log::info!("Looked up the unknown signer {}", cert.fingerprint());
+					self.fetched_certs.push(cert);
+				}
+				Err(err) => log::warn!("Could not fetch the cert of the signer {handle}: {err}"),
+			}
+		}
+
+		let concerned_certs = self
+			.public_certs
+			.iter()
+			.chain(&self.fetched_certs)
+			.filter(|cert| ids.iter().any(|handle| owns(cert, handle)))
+			.cloned()
+			.collect::<Vec<_>>();
+
+		Ok(concerned_certs)
+	}
+
+	fn decrypt(
 		&mut self,
-		pkesks: &[packet::PKESK],
-		skesks: &[packet::SKESK],
+		pkesks: &[PKESK],
+		skesks: &[SKESK],
 		sym_algo: Option<SymmetricAlgorithm>,
-		mut decrypt: D,
-	) -> sequoia_openpgp::Result<Option<Fingerprint>>
-	where
-		D: FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
-	{
+		decrypt: &mut DecryptFn<'_>,
+	) -> sequoia_openpgp::Result<Option<Fingerprint>> {
 		// First, we try those keys that we can use without prompting
 		// for a password.
 		for pkesk in pkesks {
-			if let Some(key) = self.secrets.get_mut(pkesk.recipient()) {
-				if let Some(fingerprint) =
-					key.clone().into_keypair().ok().and_then(|kp| {
-						Self::try_decrypt(pkesk, sym_algo, Box::new(kp), &mut decrypt)
-					}) {
-					return Ok(Some(fingerprint));
-				}
+			if let Some(key) = self.secrets.get(pkesk.recipient())
+				&& let Some(keypair) = self.unlocker.unlocked(key)?
+				&& let Some(fingerprint) =
+					Self::try_decrypt(pkesk, sym_algo, Box::new(keypair), decrypt)
+			{
+				return Ok(Some(fingerprint));
 			}
 		}
 
@@ -193,64 +290,80 @@ impl DecryptionHelper for ReceiveHelper<'_> {
 			}
 
 			let keyid = pkesk.recipient();
-			if let Some(key) = self.secrets.get_mut(keyid) {
-				let keypair: Box<dyn Decryptor> = loop {
-					if let Ok(keypair) = key.clone().into_keypair() {
-						break Box::new(keypair);
-					}
-
-					log::debug!("key {} is encrypted", keyid);
-
-					let key_password = rpassword::prompt_password(format!(
-						"Enter password to decrypt key {}: ",
-						self.hints
-							.get(keyid)
-							.expect("keyid come from the same source as hints")
-					))?;
 
-					match decrypt_key(key, &key_password.into()) {
-						Ok(decryptor) => break decryptor,
-						Err(error) => log::error!("Could not unlock key: {error:?}"),
+			// The agent asks for the passphrase itself, other keys and the password
+			// are still tried when it cannot be used
+			if let Some((key, agent_key)) = self.agent_keys.get(keyid) {
+				match agent_key.keypair(None, key) {
+					Ok(keypair) => {
+						if let Some(fp) =
+							Self::try_decrypt(pkesk, sym_algo, Box::new(keypair), decrypt)
+						{
+							return Ok(Some(fp));
+						}
 					}
-				};
+					Err(error) => log::warn!("Could not use the gpg-agent key {keyid}: {error}"),
+				}
+			}
+
+			if let Some(key) = self.secrets.get(keyid) {
+				let hint = self
+					.hints
+					.get(keyid)
+					.expect("keyid come from the same source as hints");
+				let keypair = Box::new(self.unlocker.unlock(key, hint)?);
 
-				if let Some(fp) = Self::try_decrypt(pkesk, sym_algo, keypair, &mut decrypt) {
+				if let Some(fp) = Self::try_decrypt(pkesk, sym_algo, keypair, decrypt) {
 					return Ok(Some(fp));
 				}
 			}
 		}
 
-		// TODO: wildcards recipients
+		// Third, we try every key on the wildcard recipients.
+		let wildcards = pkesks
+			.iter()
+			.filter(|pkesk| pkesk.recipient().is_wildcard() && pkesk.pk_algo().is_supported())
+			.collect::<Vec<_>>();
+
+		if let Some(fp) = self.try_decrypt_wildcards(&wildcards, sym_algo, decrypt)? {
+			return Ok(Some(fp));
+		}
 
 		if skesks.is_empty() {
 			return Err(anyhow::anyhow!("No key to decrypt message"));
 		}
 
 		// Finally, try to decrypt using the SKESKs.
-		loop {
-			let password =
-				rpassword::prompt_password("Enter password to decrypt message: ")?.into();
+		if let Some(password) = &self.password {
+			if try_password(skesks, password, decrypt) {
+				return Ok(None);
+			}
 
-			for skesk in skesks {
-				if let Some(_sk) = skesk.decrypt(&password).ok().and_then(|(algo, sk)| {
-					if decrypt(algo, &sk) { Some(sk) } else { None }
-				}) {
-					return Ok(None);
-				}
+			anyhow::bail!("the given password cannot decrypt the paste");
+		}
+
+		for _ in 0..prompt::MAX_ATTEMPTS {
+			let password = prompt::password(
+				"Password to decrypt the paste: ",
+				self.unlocker.interactive(),
+			)?;
+
+			if try_password(skesks, &password.as_str().into(), decrypt) {
+				return Ok(None);
 			}
 
 			log::error!("Bad password.");
 		}
+
+		anyhow::bail!("too many wrong passwords for the paste")
 	}
 }
 
-/// Decrypts an encrypted secret key
-fn decrypt_key(
-	key: &mut Key<SecretParts, UnspecifiedRole>,
-	pass: &crypto::Password,
-) -> sequoia_openpgp::Result<Box<dyn Decryptor>> {
-	let algo = key.pk_algo();
-	key.secret_mut().decrypt_in_place(algo, pass)?;
-
-	Ok(Box::new(key.clone().into_keypair()?))
+/// Whether the password decrypts one of the SKESKs
+fn try_password(skesks: &[SKESK], password: &Password, decrypt: &mut DecryptFn<'_>) -> bool {
+	skesks.iter().any(|skesk| {
+		skesk
+			.decrypt(password)
+			.is_ok_and(|(algo, sk)| decrypt(algo, &sk))
+	})
 }
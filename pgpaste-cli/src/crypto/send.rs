@@ -1,101 +1,54 @@
 //! Create and encrypt pastes.
 
-use std::{borrow::Cow, collections::HashMap, io::Write};
+use std::{borrow::Cow, collections::HashMap};
 
-use async_compat::Compat;
-use eyre::ContextCompat;
-use rpassword::prompt_password;
-use sequoia_net::{KeyServer, Policy};
+use pgpaste_client::PasteSigner;
 use sequoia_openpgp::{
 	Cert, KeyHandle, KeyID,
-	crypto::KeyPair,
-	serialize::stream::{Encryptor2, LiteralWriter, Message, Signer},
+	crypto::{self, Password},
 };
 
-use super::POLICY;
-use crate::ToEyreError;
+use super::{AgentKeys, CertLookup, KeyUnlocker, POLICY, owns};
 
 /// Signs the given message.
 pub(crate) fn sign(content: &[u8], helper: &SendHelper) -> eyre::Result<Vec<u8>> {
-	let keypair = helper.signing_key()?;
-
-	let mut signed_message: Vec<u8> = Vec::new();
-	let message = Message::new(&mut signed_message);
-	let signer = Signer::new(message, keypair).build().to_eyre()?;
-	let mut literal = LiteralWriter::new(signer).build().to_eyre()?;
-
-	literal.write_all(content)?;
-	literal.finalize().to_eyre()?;
-
-	Ok(signed_message)
+	Ok(pgpaste_client::crypto::sign(content, helper)?)
 }
 
-// IDEA: merge protect and encrypt into one function
-
-/// Signs the given message and protect it with a user password.
+/// Protects the given message with a user password, signing it first if `sign` is set.
 pub(crate) fn protect(
 	content: &[u8],
 	helper: &SendHelper,
-	password: &str,
+	password: &Password,
 	sign: bool,
 ) -> eyre::Result<Vec<u8>> {
-	// Start streaming an OpenPGP message.
-	let mut signed_message: Vec<u8> = Vec::new();
-	let message = Message::new(&mut signed_message);
-
-	let next = if sign {
-		let keypair = helper.signing_key()?;
-		Signer::new(message, keypair).build().to_eyre()?
-	} else {
-		message
-	};
-
-	let encryptor = Encryptor2::with_passwords(next, [password])
-		.build()
-		.to_eyre()?;
-	let mut literal = LiteralWriter::new(encryptor).build().to_eyre()?;
-
-	literal.write_all(content)?;
-	literal.finalize().to_eyre()?;
-
-	Ok(signed_message)
+	let signer = sign.then_some(helper as &dyn PasteSigner);
+	Ok(pgpaste_client::crypto::protect(content, password, signer)?)
 }
 
-/// Encrypts the given message.
+/// Encrypts the given message for all the given recipients, their certs are fetched
+/// from the keyservers when unknown.
+///
+/// When `obscure` is set, recipients are hidden behind wildcard key IDs.
 pub(crate) fn encrypt(
 	content: &[u8],
 	helper: &SendHelper,
-	recipient: KeyHandle,
+	recipients: &[KeyHandle],
+	obscure: bool,
 	sign: bool,
 ) -> eyre::Result<Vec<u8>> {
-	let recipient_cert = helper.get_cert(recipient)?;
-	let recipients = recipient_cert
-		.keys()
-		.with_policy(POLICY, None)
-		.alive()
-		.revoked(false)
-		.supported()
-		.for_transport_encryption();
-
-	let mut encrypted_message = Vec::new();
-	let message = Message::new(&mut encrypted_message);
-
-	let next = if sign {
-		let keypair = helper.signing_key()?;
-		Signer::new(message, keypair).build().to_eyre()?
-	} else {
-		message
-	};
-
-	let encryptor = Encryptor2::for_recipients(next, recipients)
-		.build()
-		.to_eyre()?;
-	let mut literal = LiteralWriter::new(encryptor).build().to_eyre()?;
-
-	literal.write_all(content)?;
-	literal.finalize().to_eyre()?;
-
-	Ok(encrypted_message)
+	let recipient_certs = recipients
+		.iter()
+		.map(|recipient| helper.get_cert(recipient.clone()).map(Cow::into_owned))
+		.collect::<eyre::Result<Vec<_>>>()?;
+
+	let signer = sign.then_some(helper as &dyn PasteSigner);
+	Ok(pgpaste_client::crypto::encrypt(
+		content,
+		&recipient_certs,
+		obscure,
+		signer,
+	)?)
 }
 
 /// A helper to create and encrypt pastes.
@@ -104,10 +57,16 @@ pub(crate) struct SendHelper<'a> {
 	/// The cert used for signing and/or encrypting.
 	default_cert: &'a Cert,
 
-	/// Private certs used for signing.
-	_private_certs: &'a [Cert],
+	/// Private certs used for signing, and for encrypting to ourselves.
+	private_certs: &'a [Cert],
 	/// Public certs used for encryption.
 	public_certs: &'a [Cert],
+	/// Keys whose secret material is held by a `gpg-agent`
+	agent_keys: &'a AgentKeys,
+	/// Keeps the secret keys unlocked for the whole invocation
+	unlocker: &'a KeyUnlocker,
+	/// Finds the certs of unknown recipients
+	lookup: &'a CertLookup,
 	/// Hints used when prompting the user to decrypt their key.
 	hints: HashMap<KeyID, String>,
 }
@@ -118,6 +77,9 @@ impl<'a> SendHelper<'a> {
 		default_cert_handle: &KeyHandle,
 		private_certs: &'a [Cert],
 		public_certs: &'a [Cert],
+		agent_keys: &'a AgentKeys,
+		unlocker: &'a KeyUnlocker,
+		lookup: &'a CertLookup,
 	) -> eyre::Result<Self> {
 		let mut hints = HashMap::new();
 
@@ -143,73 +105,71 @@ impl<'a> SendHelper<'a> {
 		Ok(Self {
 			default_cert,
 
-			_private_certs: private_certs,
+			private_certs,
 			public_certs,
+			agent_keys,
+			unlocker,
+			lookup,
 			hints,
 		})
 	}
 
-	/// The keypair to use when signing pastes
-	pub(crate) fn signing_key(&self) -> eyre::Result<KeyPair> {
-		let mut iter = self
-			.default_cert
-			.keys()
-			.secret()
-			.with_policy(POLICY, None)
-			.alive()
-			.revoked(false)
-			.for_signing();
-
-		let mut key = iter
-			.next()
-			.wrap_err("the default cert has no valid key for signing")?
-			.key()
-			.clone();
-
-		if key.secret().is_encrypted() {
-			loop {
-				let password = prompt_password(format!(
-					"Password to decrypt key {}: ",
-					self.hints
-						.get(&key.keyid())
-						.expect("keyid come from the same source as hints")
-				))?;
-
-				let algo = key.pk_algo();
-				if key
-					.secret_mut()
-					.decrypt_in_place(algo, &password.into())
-					.is_ok()
-				{
-					break;
-				};
-
-				log::error!("Wrong password, try again");
-			}
-		}
-
-		Ok(key.into_keypair().expect("key was decrypted"))
-	}
-
-	/// Returns the cert for the given key handle whether it is in the cache or by fetching it
+	/// Returns the cert for the given key handle whether it is known or by looking it up,
+	/// the handle may be the one of a subkey
 	fn get_cert(&self, recipient: KeyHandle) -> eyre::Result<Cow<'a, Cert>> {
 		let cached_cert = self
 			.public_certs
 			.iter()
-			.find(|c| c.key_handle() == recipient);
+			.chain(self.private_certs)
+			.find(|c| owns(c, &recipient));
 
 		let cert = match cached_cert {
 			Some(cert) => Cow::Borrowed(cert),
-			None => Cow::Owned(fetch_key_handle(recipient)?),
+			None => Cow::Owned(self.lookup.get(&recipient)?),
 		};
 
 		Ok(cert)
 	}
 }
 
-/// Fetches the given key from the wellknown `OpenPGP` keyserver.
-fn fetch_key_handle(key: KeyHandle) -> eyre::Result<Cert> {
-	let mut key_server = KeyServer::keys_openpgp_org(Policy::Encrypted).to_eyre()?;
+impl PasteSigner for SendHelper<'_> {
+	fn signer(&self) -> sequoia_openpgp::Result<Box<dyn crypto::Signer + Send + Sync>> {
+		if let Some(agent_key) = self.agent_keys.get(&self.default_cert.fingerprint()) {
+			let ka = self
+				.default_cert
+				.keys()
+				.with_policy(POLICY, None)
+				.alive()
+				.revoked(false)
+				.for_signing()
+				.next()
+				.ok_or_else(|| anyhow::anyhow!("the default cert has no valid key for signing"))?;
+
+			let keypair =
+				agent_key.keypair(Some(self.default_cert), ka.key().role_as_unspecified())?;
+			return Ok(Box::new(keypair));
+		}
+
+		let key = self
+			.default_cert
+			.keys()
+			.secret()
+			.with_policy(POLICY, None)
+			.alive()
+			.revoked(false)
+			.for_signing()
+			.next()
+			.ok_or_else(|| anyhow::anyhow!("the default cert has no valid key for signing"))?
+			.key()
+			.role_as_unspecified()
+			.clone();
+
+		let hint = self
+			.hints
+			.get(&self.default_cert.keyid())
+			.expect("the default cert is one of the private certs");
+		let keypair = self.unlocker.unlock(&key, hint)?;
 
-	smol::block_on(Compat::new(async { key_server.get(key).await.to_eyre() }))
+		Ok(Box::new(keypair))
+	}
 }
@@ -0,0 +1,197 @@
+//! Unlock secret keys once per invocation
+
+use std::{
+	collections::HashMap,
+	sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use sequoia_openpgp::{
+	KeyID,
+	crypto::{self, KeyPair, Password, SessionKey, mpi},
+	packet::{
+		Key,
+		key::{PublicParts, SecretParts, UnspecifiedRole},
+	},
+	types::HashAlgorithm,
+};
+
+#[cfg(unix)]
+use crate::daemon::{self, DaemonKeyPair};
+use crate::prompt;
+
+/// An unlocked key, either in this process or held by the agent daemon
+#[derive(Clone)]
+pub(crate) enum UnlockedKey {
+	/// The secret is in this process memory
+	Local(KeyPair),
+	/// The secret is held by the agent daemon
+	#[cfg(unix)]
+	Daemon(DaemonKeyPair),
+}
+
+impl crypto::Signer for UnlockedKey {
+	fn public(&self) -> &Key<PublicParts, UnspecifiedRole> {
+		match self {
+			Self::Local(keypair) => crypto::Signer::public(keypair),
+			#[cfg(unix)]
+			Self::Daemon(keypair) => crypto::Signer::public(keypair),
+		}
+	}
+
+	fn sign(
+		&mut self,
+		hash_algo: HashAlgorithm,
+		digest: &[u8],
+	) -> sequoia_openpgp::Result<mpi::Signature> {
+		match self {
+			Self::Local(keypair) => keypair.sign(hash_algo, digest),
+			#[cfg(unix)]
+			Self::Daemon(keypair) => keypair.sign(hash_algo, digest),
+		}
+	}
+}
+
+impl crypto::Decryptor for UnlockedKey {
+	fn public(&self) -> &Key<PublicParts, UnspecifiedRole> {
+		match self {
+			Self::Local(keypair) => crypto::Decryptor::public(keypair),
+			#[cfg(unix)]
+			Self::Daemon(keypair) => crypto::Decryptor::public(keypair),
+		}
+	}
+
+	fn decrypt(
+		&mut self,
+		ciphertext: &mpi::Ciphertext,
+		plaintext_len: Option<usize>,
+	) -> sequoia_openpgp::Result<SessionKey> {
+		match self {
+			Self::Local(keypair) => keypair.decrypt(ciphertext, plaintext_len),
+			#[cfg(unix)]
+			Self::Daemon(keypair) => keypair.decrypt(ciphertext, plaintext_len),
+		}
+	}
+}
+
+/// Decrypts secret keys on demand and keeps them unlocked for the life of the process,
+/// so that each key is asked for at most once, or longer when the agent daemon runs
+#[derive(Debug)]
+pub(crate) struct KeyUnlocker {
+	/// The decrypted secret keys
+	unlocked: Mutex<HashMap<KeyID, Key<SecretParts, UnspecifiedRole>>>,
+	/// Whether the user may be prompted for passwords
+	interactive: bool,
+}
+
+impl KeyUnlocker {
+	/// Creates an unlocker, which fails instead of prompting when not `interactive`
+	pub(crate) fn new(interactive: bool) -> Self {
+		Self {
+			unlocked: Mutex::default(),
+			interactive,
+		}
+	}
+
+	/// Whether the user may be prompted for passwords
+	pub(crate) const fn interactive(&self) -> bool {
+		self.interactive
+	}
+
+	/// Returns a keypair if the key is not encrypted or was already unlocked,
+	/// by this process or by the agent daemon
+	pub(crate) fn unlocked(
+		&self,
+		key: &Key<SecretParts, UnspecifiedRole>,
+	) -> sequoia_openpgp::Result<Option<UnlockedKey>> {
+		if !key.secret().is_encrypted() {
+			return key
+				.clone()
+				.into_keypair()
+				.map(|k| Some(UnlockedKey::Local(k)));
+		}
+
+		if let Some(key) = self.lock().get(&key.keyid()) {
+			return key
+				.clone()
+				.into_keypair()
+				.map(|k| Some(UnlockedKey::Local(k)));
+		}
+
+		#[cfg(unix)]
+		if daemon::has(&key.fingerprint()) {
+			log::debug!("key {} is held by the agent", key.keyid());
+			let public = key.parts_as_public().clone();
+			return Ok(Some(UnlockedKey::Daemon(DaemonKeyPair::new(public))));
+		}
+
+		Ok(None)
+	}
+
+	/// Returns a keypair, asking for the password until it is right or
+	/// there were too many attempts
+	pub(crate) fn unlock(
+		&self,
+		key: &Key<SecretParts, UnspecifiedRole>,
+		hint: &str,
+	) -> sequoia_openpgp::Result<UnlockedKey> {
+		for _ in 0..prompt::MAX_ATTEMPTS {
+			if let Some(keypair) = self.try_unlock(key, hint)? {
+				return Ok(keypair);
+			}
+		}
+
+		anyhow::bail!("too many wrong passwords for key {hint}")
+	}
+
+	/// Returns a keypair, asking for the password once, `None` if it is wrong
+	pub(crate) fn try_unlock(
+		&self,
+		key: &Key<SecretParts, UnspecifiedRole>,
+		hint: &str,
+	) -> sequoia_openpgp::Result<Option<UnlockedKey>> {
+		if let Some(keypair) = self.unlocked(key)? {
+			return Ok(Some(keypair));
+		}
+
+		log::debug!("key {} is encrypted", key.keyid());
+
+		let password = prompt::password(
+			&format!("Password to decrypt key {hint}: "),
+			self.interactive,
+		)?;
+
+		let mut decrypted = key.clone();
+		let algo = decrypted.pk_algo();
+		if let Err(error) = decrypted
+			.secret_mut()
+			.decrypt_in_place(algo, &Password::from(password.as_str()))
+		{
+			log::error!("Could not unlock key: {error}");
+			return Ok(None);
+		}
+
+		// Later invocations will not have to ask again
+		#[cfg(unix)]
+		if let Err(error) = daemon::store(&decrypted) {
+			log::debug!("could not give key {} to the agent: {error}", key.keyid());
+		}
+
+		let keypair = decrypted.clone().into_keypair()?;
+		self.lock().insert(key.keyid(), decrypted);
+
+		Ok(Some(UnlockedKey::Local(keypair)))
+	}
+
+	/// Lock the cache, a poisoned cache is still usable
+	fn lock(&self) -> MutexGuard<'_, HashMap<KeyID, Key<SecretParts, UnspecifiedRole>>> {
+		self.unlocked.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+impl Drop for KeyUnlocker {
+	fn drop(&mut self) {
+		// Sequoia keeps unencrypted secrets in `Protected` memory which is
+		// zeroed when freed, drop them all right away
+		self.lock().clear();
+	}
+}
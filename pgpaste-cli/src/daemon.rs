@@ -0,0 +1,615 @@
+//! Local agent daemon keeping unlocked keys across invocations
+//!
+//! The agent listens on a Unix socket in `$XDG_RUNTIME_DIR`, each connection carries one
+//! length-prefixed `MsgPack` request and its response.
+
+use std::{
+	collections::HashMap,
+	fs,
+	io::{self, Read, Write},
+	os::unix::{
+		fs::PermissionsExt,
+		net::{UnixListener, UnixStream},
+	},
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, MutexGuard, PoisonError},
+	thread,
+	time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use eyre::ContextCompat;
+use sequoia_openpgp::{
+	Fingerprint, Packet,
+	crypto::{self, SessionKey, mpi},
+	packet::{
+		Key,
+		key::{PublicParts, SecretParts, UnspecifiedRole},
+	},
+	parse::Parse,
+	serialize::MarshalInto,
+	types::HashAlgorithm,
+};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::ToEyreError;
+
+/// The largest frame accepted, requests only carry a key packet or a digest
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// How long a client may take to send its request or read the response
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often expired keys are forgotten, shorter when keys live less than that
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Requests sent by the CLI to the agent
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Request {
+	/// Keep an unlocked secret key, serialized as a secret key packet
+	Store {
+		/// The serialized packet
+		key: Vec<u8>,
+	},
+	/// Whether the agent holds a key
+	Has {
+		/// The fingerprint of the key
+		fingerprint: String,
+	},
+	/// Sign a digest with a held key
+	Sign {
+		/// The fingerprint of the key
+		fingerprint: String,
+		/// The hash algorithm used for the digest
+		hash_algo: u8,
+		/// The digest to sign
+		digest: Vec<u8>,
+	},
+	/// Decrypt a session key with a held key
+	Decrypt {
+		/// The fingerprint of the key
+		fingerprint: String,
+		/// The serialized ciphertext MPIs
+		ciphertext: Vec<u8>,
+		/// The expected length of the session key
+		plaintext_len: Option<usize>,
+	},
+	/// Forget all the held keys
+	Lock,
+}
+
+/// Responses sent by the agent
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Response {
+	/// The request was handled
+	Done,
+	/// Whether the agent holds the key
+	Has(bool),
+	/// The serialized signature MPIs
+	Signature(Vec<u8>),
+	/// The decrypted session key
+	SessionKey(Vec<u8>),
+	/// The request failed
+	Error(String),
+}
+
+/// The path of the agent socket
+pub(crate) fn socket_path() -> eyre::Result<PathBuf> {
+	let directory = dirs::runtime_dir().wrap_err("`XDG_RUNTIME_DIR` is not set")?;
+
+	Ok(directory.join("pgpaste").join("agent.sock"))
+}
+
+/// Send a request to the running agent
+pub(crate) fn send(request: &Request) -> sequoia_openpgp::Result<Response> {
+	let path = socket_path().map_err(|error| anyhow::anyhow!("{error}"))?;
+	send_to(&path, request)
+}
+
+/// Send a request to the agent listening on the given socket
+fn send_to(path: &Path, request: &Request) -> sequoia_openpgp::Result<Response> {
+	let mut stream = UnixStream::connect(path).context("no agent is running")?;
+
+	let mut buffer = rmp_serde::to_vec(request)?;
+	let written = write_frame(&mut stream, &buffer);
+	buffer.zeroize();
+	written?;
+
+	let mut buffer = read_frame(&mut stream)?;
+	let response = rmp_serde::from_slice(&buffer);
+	buffer.zeroize();
+
+	Ok(response?)
+}
+
+/// Give an unlocked key to the running agent
+pub(crate) fn store(key: &Key<SecretParts, UnspecifiedRole>) -> sequoia_openpgp::Result<()> {
+	let packet = Packet::from(key.clone().role_into_subordinate());
+
+	let request = Request::Store {
+		key: packet.to_vec()?,
+	};
+	let response = send(&request);
+
+	// The serialized key is not encrypted
+	if let Request::Store { mut key } = request {
+		key.zeroize();
+	}
+
+	match response? {
+		Response::Done => Ok(()),
+		response => Err(unexpected(response)),
+	}
+}
+
+/// Whether the running agent holds the given key
+pub(crate) fn has(fingerprint: &Fingerprint) -> bool {
+	let request = Request::Has {
+		fingerprint: fingerprint.to_hex(),
+	};
+
+	matches!(send(&request), Ok(Response::Has(true)))
+}
+
+/// A keypair whose secret is held by the agent daemon
+#[derive(Debug, Clone)]
+pub(crate) struct DaemonKeyPair {
+	/// The public part of the key
+	public: Key<PublicParts, UnspecifiedRole>,
+}
+
+impl DaemonKeyPair {
+	/// A keypair for a key the agent holds
+	pub(crate) const fn new(public: Key<PublicParts, UnspecifiedRole>) -> Self {
+		Self { public }
+	}
+}
+
+impl crypto::Signer for DaemonKeyPair {
+	fn public(&self) -> &Key<PublicParts, UnspecifiedRole> {
+		&self.public
+	}
+
+	fn sign(
+		&mut self,
+		hash_algo: HashAlgorithm,
+		digest: &[u8],
+	) -> sequoia_openpgp::Result<mpi::Signature> {
+		let request = Request::Sign {
+			fingerprint: self.public.fingerprint().to_hex(),
+			hash_algo: hash_algo.into(),
+			digest: digest.to_vec(),
+		};
+
+		match send(&request)? {
+			Response::Signature(signature) => {
+				mpi::Signature::parse(self.public.pk_algo(), signature.as_slice())
+			}
+			response => Err(unexpected(response)),
+		}
+	}
+}
+
+impl crypto::Decryptor for DaemonKeyPair {
+	fn public(&self) -> &Key<PublicParts, UnspecifiedRole> {
+		&self.public
+	}
+
+	fn decrypt(
+		&mut self,
+		ciphertext: &mpi::Ciphertext,
+		plaintext_len: Option<usize>,
+	) -> sequoia_openpgp::Result<SessionKey> {
+		let request = Request::Decrypt {
+			fingerprint: self.public.fingerprint().to_hex(),
+			ciphertext: ciphertext.to_vec()?,
+			plaintext_len,
+		};
+
+		match send(&request)? {
+			Response::SessionKey(session_key) => Ok(session_key.into()),
+			response => Err(unexpected(response)),
+		}
+	}
+}
+
+/// Turn an error or an unexpected response into an error
+fn unexpected(response: Response) -> anyhow::Error {
+	match response {
+		Response::Error(error) => anyhow::anyhow!("agent error: {error}"),
+		response => anyhow::anyhow!("unexpected agent response: {response:?}"),
+	}
+}
+
+/// The keys held by the agent and when they expire
+type HeldKeys = HashMap<Fingerprint, (Key<SecretParts, UnspecifiedRole>, Instant)>;
+
+/// The held keys shared between the connections and the purge timer
+type SharedKeys = Arc<Mutex<HeldKeys>>;
+
+/// Run the agent until it is killed
+pub(crate) fn serve(ttl: Duration) -> eyre::Result<()> {
+	let path = socket_path()?;
+
+	if UnixStream::connect(&path).is_ok() {
+		eyre::bail!("an agent is already running on `{}`", path.display());
+	}
+
+	let directory = path.parent().wrap_err("socket path has no parent")?;
+	fs::create_dir_all(directory)?;
+	// Only the user may talk to the agent
+	fs::set_permissions(directory, fs::Permissions::from_mode(0o700))?;
+
+	// A previous agent did not clean up after itself
+	if path.exists() {
+		fs::remove_file(&path)?;
+	}
+
+	let listener = UnixListener::bind(&path)?;
+	log::info!(
+		"Agent listening on `{}`, keys are kept for {}",
+		path.display(),
+		humantime::format_duration(ttl)
+	);
+
+	run(listener, &SharedKeys::default(), ttl);
+
+	Ok(())
+}
+
+/// Answer the connections of the listener, each on its own thread, while expired
+/// keys are forgotten on a timer
+fn run(listener: UnixListener, keys: &SharedKeys, ttl: Duration) {
+	let purged_keys = Arc::clone(keys);
+	thread::spawn(move || {
+		loop {
+			thread::sleep(PURGE_INTERVAL.min(ttl));
+
+			let now = Instant::now();
+			lock_keys(&purged_keys).retain(|_, (_, expires_at)| *expires_at > now);
+		}
+	});
+
+	for stream in listener.incoming() {
+		let mut stream = match stream {
+			Ok(stream) => stream,
+			Err(error) => {
+				log::error!("could not accept connection: {error}");
+				continue;
+			}
+		};
+
+		let keys = Arc::clone(keys);
+		thread::spawn(move || {
+			if let Err(error) = handle_connection(&mut stream, &keys, ttl) {
+				log::error!("could not handle request: {error}");
+			}
+		});
+	}
+}
+
+/// Lock the held keys, a panicking connection does not leave them inconsistent
+fn lock_keys(keys: &SharedKeys) -> MutexGuard<'_, HeldKeys> {
+	keys.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Answer the single request of a connection
+fn handle_connection(
+	stream: &mut UnixStream,
+	keys: &SharedKeys,
+	ttl: Duration,
+) -> eyre::Result<()> {
+	// A client that stalls must not keep a thread forever
+	stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+	stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+
+	let mut buffer = match read_frame(stream) {
+		Ok(buffer) => buffer,
+		// Another agent checking whether this one is running
+		Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+		Err(error) => return Err(error.into()),
+	};
+	let request = rmp_serde::from_slice::<Request>(&buffer);
+	buffer.zeroize();
+
+	let response = match request {
+		Ok(request) => handle_request(request, &mut lock_keys(keys), ttl)
+			.unwrap_or_else(|error| Response::Error(error.to_string())),
+		Err(error) => Response::Error(error.to_string()),
+	};
+
+	let mut buffer = rmp_serde::to_vec(&response)?;
+	let written = write_frame(stream, &buffer);
+	buffer.zeroize();
+
+	if let Response::SessionKey(mut session_key) = response {
+		session_key.zeroize();
+	}
+
+	Ok(written?)
+}
+
+/// Execute a request on the held keys
+fn handle_request(
+	request: Request,
+	keys: &mut HeldKeys,
+	ttl: Duration,
+) -> sequoia_openpgp::Result<Response> {
+	let keypair = |fingerprint: &str| {
+		let fingerprint = fingerprint.parse::<Fingerprint>()?;
+		let (key, _) = keys
+			.get(&fingerprint)
+			.ok_or_else(|| anyhow::anyhow!("key is not held"))?;
+		key.clone().into_keypair()
+	};
+
+	let response = match request {
+		Request::Store { mut key } => {
+			let packet = Packet::from_bytes(&key);
+			key.zeroize();
+
+			let key = match packet? {
+				Packet::SecretKey(key) => key.role_into_unspecified(),
+				Packet::SecretSubkey(key) => key.role_into_unspecified(),
+				_ => anyhow::bail!("not a secret key"),
+			};
+			if key.secret().is_encrypted() {
+				anyhow::bail!("key is still encrypted");
+			}
+
+			log::info!("Holding key {}", key.fingerprint());
+			keys.insert(key.fingerprint(), (key, Instant::now() + ttl));
+
+			Response::Done
+		}
+		Request::Has { fingerprint } => {
+			let fingerprint = fingerprint.parse::<Fingerprint>()?;
+			Response::Has(keys.contains_key(&fingerprint))
+		}
+		Request::Sign {
+			fingerprint,
+			hash_algo,
+			digest,
+		} => {
+			let mut keypair = keypair(&fingerprint)?;
+			let signature = crypto::Signer::sign(&mut keypair, hash_algo.into(), &digest)?;
+
+			Response::Signature(signature.to_vec()?)
+		}
+		Request::Decrypt {
+			fingerprint,
+			ciphertext,
+			plaintext_len,
+		} => {
+			let mut keypair = keypair(&fingerprint)?;
+			let ciphertext = mpi::Ciphertext::parse(keypair.public().pk_algo(), &*ciphertext)?;
+			let session_key = crypto::Decryptor::decrypt(&mut keypair, &ciphertext, plaintext_len)?;
+
+			Response::SessionKey(session_key.to_vec())
+		}
+		Request::Lock => {
+			log::info!("Forgetting {} key(s)", keys.len());
+			keys.clear();
+
+			Response::Done
+		}
+	};
+
+	Ok(response)
+}
+
+/// Write a length-prefixed frame
+fn write_frame(stream: &mut UnixStream, payload: &[u8]) -> io::Result<()> {
+	let len = u32::try_from(payload.len())
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame is too large"))?;
+
+	stream.write_all(&len.to_be_bytes())?;
+	stream.write_all(payload)?;
+	stream.flush()
+}
+
+/// Read a length-prefixed frame
+fn read_frame(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+	let mut len = [0; 4];
+	stream.read_exact(&mut len)?;
+
+	let len = u32::from_be_bytes(len) as usize;
+	if len > MAX_FRAME_LEN {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("frame of {len} bytes is too large"),
+		));
+	}
+
+	let mut payload = vec![0; len];
+	stream.read_exact(&mut payload)?;
+
+	Ok(payload)
+}
+
+/// Forget the keys held by the running agent
+pub(crate) fn lock() -> eyre::Result<()> {
+	match send(&Request::Lock).to_eyre()? {
+		Response::Done => Ok(()),
+		response => Err(unexpected(response)).to_eyre(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		io::Write,
+		os::unix::net::{UnixListener, UnixStream},
+		path::PathBuf,
+		sync::Arc,
+		thread,
+		time::Duration,
+	};
+
+	use anyhow::Context as _;
+	use sequoia_openpgp::{
+		Packet,
+		cert::CertBuilder,
+		crypto::mpi,
+		packet::{
+			Key,
+			key::{SecretParts, UnspecifiedRole},
+		},
+		serialize::MarshalInto,
+		types::HashAlgorithm,
+	};
+	use tempfile::TempDir;
+
+	use super::{Request, Response, SharedKeys, lock_keys, run, send_to};
+
+	/// An agent answering on a socket in a temporary directory
+	struct TestAgent {
+		/// Removed with the socket when the test ends
+		_directory: TempDir,
+		/// The socket of the agent
+		path: PathBuf,
+		/// The keys held by the agent
+		keys: SharedKeys,
+	}
+
+	impl TestAgent {
+		/// Start an agent keeping keys for the given duration
+		fn spawn(ttl: Duration) -> sequoia_openpgp::Result<Self> {
+			let directory = tempfile::tempdir()?;
+			let path = directory.path().join("agent.sock");
+			let listener = UnixListener::bind(&path)?;
+
+			let keys = SharedKeys::default();
+			let agent_keys = Arc::clone(&keys);
+			thread::spawn(move || run(listener, &agent_keys, ttl));
+
+			Ok(Self {
+				_directory: directory,
+				path,
+				keys,
+			})
+		}
+
+		/// Send a request and return the response
+		fn send(&self, request: &Request) -> sequoia_openpgp::Result<Response> {
+			send_to(&self.path, request)
+		}
+
+		/// Give an unlocked key to the agent
+		fn store(&self, key: &Key<SecretParts, UnspecifiedRole>) -> sequoia_openpgp::Result<()> {
+			let packet = Packet::from(key.clone().role_into_subordinate());
+			match self.send(&Request::Store {
+				key: packet.to_vec()?,
+			})? {
+				Response::Done => Ok(()),
+				response => anyhow::bail!("unexpected response: {response:?}"),
+			}
+		}
+
+		/// Whether the agent holds the key
+		fn has(&self, key: &Key<SecretParts, UnspecifiedRole>) -> sequoia_openpgp::Result<bool> {
+			match self.send(&Request::Has {
+				fingerprint: key.fingerprint().to_hex(),
+			})? {
+				Response::Has(has) => Ok(has),
+				response => anyhow::bail!("unexpected response: {response:?}"),
+			}
+		}
+	}
+
+	/// An unlocked signing subkey of a fresh cert
+	fn signing_key() -> sequoia_openpgp::Result<Key<SecretParts, UnspecifiedRole>> {
+		let (cert, _) = CertBuilder::new().add_signing_subkey().generate()?;
+		let key = cert
+			.keys()
+			.subkeys()
+			.secret()
+			.next()
+			.context("the cert has no subkey")?
+			.key()
+			.clone()
+			.role_into_unspecified();
+
+		Ok(key)
+	}
+
+	#[test]
+	fn store_sign_lock() -> sequoia_openpgp::Result<()> {
+		let agent = TestAgent::spawn(Duration::from_secs(60))?;
+		let key = signing_key()?;
+
+		assert!(!agent.has(&key)?);
+		agent.store(&key)?;
+		assert!(agent.has(&key)?);
+
+		let digest = [0x42; 32];
+		let response = agent.send(&Request::Sign {
+			fingerprint: key.fingerprint().to_hex(),
+			hash_algo: HashAlgorithm::SHA256.into(),
+			digest: digest.to_vec(),
+		})?;
+		let Response::Signature(signature) = response else {
+			anyhow::bail!("unexpected response: {response:?}");
+		};
+		let signature = mpi::Signature::parse(key.pk_algo(), signature.as_slice())?;
+		key.verify(&signature, HashAlgorithm::SHA256, &digest)?;
+
+		assert!(matches!(agent.send(&Request::Lock)?, Response::Done));
+		assert!(!agent.has(&key)?);
+
+		let response = agent.send(&Request::Sign {
+			fingerprint: key.fingerprint().to_hex(),
+			hash_algo: HashAlgorithm::SHA256.into(),
+			digest: digest.to_vec(),
+		})?;
+		assert!(matches!(response, Response::Error(_)));
+
+		Ok(())
+	}
+
+	#[test]
+	fn keys_expire_without_connections() -> sequoia_openpgp::Result<()> {
+		let agent = TestAgent::spawn(Duration::from_millis(200))?;
+		let key = signing_key()?;
+
+		agent.store(&key)?;
+		assert_eq!(lock_keys(&agent.keys).len(), 1);
+
+		// Nobody connects, the timer alone forgets the key
+		thread::sleep(Duration::from_millis(600));
+		assert!(lock_keys(&agent.keys).is_empty());
+
+		Ok(())
+	}
+
+	#[test]
+	fn stalled_client_does_not_block_others() -> sequoia_openpgp::Result<()> {
+		let agent = TestAgent::spawn(Duration::from_secs(60))?;
+		let key = signing_key()?;
+
+		// Connects and never sends its request
+		let _stalled = UnixStream::connect(&agent.path)?;
+
+		agent.store(&key)?;
+		assert!(agent.has(&key)?);
+
+		Ok(())
+	}
+
+	#[test]
+	fn oversized_frame_is_rejected() -> sequoia_openpgp::Result<()> {
+		let agent = TestAgent::spawn(Duration::from_secs(60))?;
+		let key = signing_key()?;
+
+		let mut stream = UnixStream::connect(&agent.path)?;
+		stream.write_all(&u32::MAX.to_be_bytes())?;
+		assert!(super::read_frame(&mut stream).is_err());
+
+		// The agent is still answering
+		agent.store(&key)?;
+		assert!(agent.has(&key)?);
+
+		Ok(())
+	}
+}
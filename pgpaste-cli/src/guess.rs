@@ -0,0 +1,81 @@
+//! Guess the mime type of a paste content
+
+use std::path::Path;
+
+use infer::MatcherType;
+use mime::Mime;
+
+/// Language hints for source files, checked before the generic extension database
+/// so that the server viewers can highlight them
+const LANGUAGES: &[(&str, &str)] = &[
+	("rs", "text/x-rust"),
+	("py", "text/x-python"),
+	("js", "text/javascript"),
+	("mjs", "text/javascript"),
+	("ts", "text/x-typescript"),
+	("go", "text/x-go"),
+	("c", "text/x-c"),
+	("h", "text/x-c"),
+	("cc", "text/x-c++"),
+	("cpp", "text/x-c++"),
+	("hpp", "text/x-c++"),
+	("java", "text/x-java"),
+	("kt", "text/x-kotlin"),
+	("rb", "text/x-ruby"),
+	("php", "text/x-php"),
+	("lua", "text/x-lua"),
+	("hs", "text/x-haskell"),
+	("swift", "text/x-swift"),
+	("nix", "text/x-nix"),
+	("sql", "text/x-sql"),
+	("sh", "text/x-shellscript"),
+	("bash", "text/x-shellscript"),
+	("zsh", "text/x-shellscript"),
+	("toml", "text/x-toml"),
+	("yaml", "text/x-yaml"),
+	("yml", "text/x-yaml"),
+	("json", "application/json"),
+	("md", "text/markdown"),
+	("diff", "text/x-diff"),
+	("patch", "text/x-diff"),
+];
+
+/// Guess the mime type of a content from its magic bytes and its file extension
+pub(crate) fn guess_mime(path: Option<&Path>, content: &[u8]) -> Mime {
+	// Magic bytes are more reliable than extensions for binary formats
+	if let Some(kind) = infer::get(content)
+		&& kind.matcher_type() != MatcherType::Text
+		&& let Ok(mime) = kind.mime_type().parse()
+	{
+		return mime;
+	}
+
+	if let Some(extension) = path
+		.and_then(Path::extension)
+		.and_then(|extension| extension.to_str())
+	{
+		let extension = extension.to_ascii_lowercase();
+
+		if let Some((_, language)) = LANGUAGES.iter().find(|(ext, _)| *ext == extension)
+			&& let Ok(mime) = language.parse()
+		{
+			return mime;
+		}
+
+		if let Some(mime) = mime_guess::from_ext(&extension).first() {
+			return mime;
+		}
+	}
+
+	if let Some(kind) = infer::get(content)
+		&& let Ok(mime) = kind.mime_type().parse()
+	{
+		return mime;
+	}
+
+	if std::str::from_utf8(content).is_ok() {
+		mime::TEXT_PLAIN
+	} else {
+		mime::APPLICATION_OCTET_STREAM
+	}
+}
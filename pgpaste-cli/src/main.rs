@@ -2,22 +2,53 @@
 
 use std::{fmt::Display, io};
 
+use async_compat::Compat;
 use clap::{CommandFactory, Parser};
 use clap_complete::generate;
 use config::Config;
 use eyre::Context;
+use log::LevelFilter;
 
 mod args;
 mod commands;
 mod config;
 mod crypto;
+#[cfg(unix)]
+mod daemon;
+mod guess;
+mod output;
+mod prompt;
 
 use crate::args::{Commands, PGPasteArgs};
 
 fn main() -> eyre::Result<()> {
-	pretty_env_logger::init();
-
 	let args = PGPasteArgs::parse();
+
+	pretty_env_logger::formatted_builder()
+		.filter_level(if args.quiet {
+			LevelFilter::Warn
+		} else {
+			LevelFilter::Info
+		})
+		.parse_default_env()
+		.init();
+
+	let format = args.format;
+	let result = run(args);
+
+	// Scripts get the error on the standard output like any other result
+	if format.is_json()
+		&& let Err(report) = &result
+	{
+		output::print_error(report);
+		std::process::exit(1);
+	}
+
+	result
+}
+
+/// Execute the parsed command
+fn run(args: PGPasteArgs) -> eyre::Result<()> {
 	let config = Config::new(&args)?;
 
 	if let Some(generator) = args.generate {
@@ -35,6 +66,11 @@ fn main() -> eyre::Result<()> {
 		match command {
 			Commands::Create(create_args) => commands::create(create_args, &config)?,
 			Commands::Read(read_args) => commands::read(read_args, &config)?,
+			Commands::Edit(edit_args) => commands::edit(&edit_args, &config)?,
+			Commands::Inbox(inbox_args) => commands::inbox(inbox_args, &config)?,
+			Commands::Init(init_args) => commands::init(init_args, &config)?,
+			Commands::Key(key_args) => commands::key(key_args, &config)?,
+			Commands::Agent(agent_args) => commands::agent(&agent_args, &config)?,
 		}
 	};
 
@@ -62,3 +98,9 @@ impl<T> ToEyreError<T> for sequoia_openpgp::Result<T> {
 		self.map_err(|err| eyre::eyre!(Box::new(err)))
 	}
 }
+
+/// Drive a future of the async client or of sequoia to completion, providing the
+/// tokio context `reqwest` expects
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+	smol::block_on(Compat::new(future))
+}
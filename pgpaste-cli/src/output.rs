@@ -0,0 +1,87 @@
+//! Machine-readable output of the commands
+
+use std::{
+	io::{self, Write},
+	time::SystemTime,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// How the commands report their results on the standard output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Format {
+	/// Human readable text, details are logged on the standard error
+	#[default]
+	Text,
+	/// A single JSON object per invocation
+	Json,
+}
+
+impl Format {
+	/// Whether the results are printed as JSON
+	pub(crate) fn is_json(self) -> bool {
+		self == Self::Json
+	}
+}
+
+/// Print a value as a single line of JSON on the standard output
+pub(crate) fn print_json<T: Serialize>(value: &T) -> eyre::Result<()> {
+	let mut stdout = io::stdout().lock();
+	serde_json::to_writer(&mut stdout, value)?;
+	writeln!(stdout)?;
+
+	Ok(stdout.flush()?)
+}
+
+/// An error as reported in JSON
+#[derive(Debug, Serialize)]
+struct ErrorOutput {
+	/// The outermost error message
+	error: String,
+	/// The messages of the underlying errors, outermost first
+	causes: Vec<String>,
+}
+
+/// Print an error as a JSON object on the standard output
+pub(crate) fn print_error(report: &eyre::Report) {
+	let error = ErrorOutput {
+		error: report.to_string(),
+		causes: report.chain().skip(1).map(ToString::to_string).collect(),
+	};
+
+	if let Err(error) = print_json(&error) {
+		log::error!("could not print the error: {error}");
+	}
+}
+
+/// A time formatted as RFC 3339
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+	humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Bytes as UTF-8 text when possible, base64 otherwise
+#[derive(Debug, Serialize)]
+pub(crate) struct Content {
+	/// Either `utf-8` or `base64`
+	encoding: &'static str,
+	/// The encoded bytes
+	content: String,
+}
+
+impl Content {
+	/// Encode bytes in the most readable way
+	pub(crate) fn new(bytes: &[u8]) -> Self {
+		match str::from_utf8(bytes) {
+			Ok(text) => Self {
+				encoding: "utf-8",
+				content: text.to_owned(),
+			},
+			Err(_) => Self {
+				encoding: "base64",
+				content: STANDARD.encode(bytes),
+			},
+		}
+	}
+}
@@ -0,0 +1,40 @@
+//! Ask the user for missing input on the terminal
+
+use std::io::{self, BufRead, Write};
+
+use zeroize::Zeroizing;
+
+/// How many wrong passwords are accepted before giving up
+pub(crate) const MAX_ATTEMPTS: usize = 3;
+
+/// Ask the user for a password without echoing it
+pub(crate) fn password(prompt: &str, interactive: bool) -> io::Result<Zeroizing<String>> {
+	ensure_interactive(prompt, interactive)?;
+
+	rpassword::prompt_password(prompt).map(Zeroizing::new)
+}
+
+/// Ask the user for a single line of input
+pub(crate) fn line(prompt: &str, interactive: bool) -> io::Result<String> {
+	ensure_interactive(prompt, interactive)?;
+
+	eprint!("{prompt}");
+	io::stderr().flush()?;
+
+	let mut line = String::new();
+	io::stdin().lock().read_line(&mut line)?;
+
+	Ok(line.trim().to_owned())
+}
+
+/// Fail instead of prompting when the CLI runs without a user
+fn ensure_interactive(prompt: &str, interactive: bool) -> io::Result<()> {
+	if interactive {
+		return Ok(());
+	}
+
+	Err(io::Error::other(format!(
+		"cannot ask for `{}`, prompting is disabled by `--no-interactive`",
+		prompt.trim_end_matches([':', ' '])
+	)))
+}
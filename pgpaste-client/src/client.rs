@@ -0,0 +1,200 @@
+//! The HTTP client of the `pgpaste` API
+
+use std::time::SystemTime;
+
+use pgpaste_api_types::api::{
+	CreateBody, CreateResponse, InboxBody, InboxResponse, ReadQuery, ReadResponse,
+	RevisionsResponse,
+};
+use reqwest::{Method, Response, StatusCode, header};
+use sequoia_openpgp::crypto::Password;
+use serde::de::DeserializeOwned;
+use url::Url;
+
+use crate::{
+	Error, Result,
+	crypto::{PasteSigner, sign},
+};
+
+/// The content type of the bodies signed by the client
+const SIGNED_CONTENT_TYPE: &str = "application/pgp-signature";
+
+/// A client for one `pgpaste` server
+#[derive(Debug, Clone)]
+pub struct Client {
+	/// The underlying HTTP client
+	http: reqwest::Client,
+	/// The base URL of the server
+	server: Url,
+}
+
+impl Client {
+	/// Creates a client for the server at the given base URL
+	#[must_use]
+	pub fn new(server: Url) -> Self {
+		Self::with_http_client(reqwest::Client::default(), server)
+	}
+
+	/// Creates a client reusing an already configured HTTP client
+	#[must_use]
+	pub const fn with_http_client(http: reqwest::Client, server: Url) -> Self {
+		Self { http, server }
+	}
+
+	/// The base URL of the server
+	#[must_use]
+	pub const fn server(&self) -> &Url {
+		&self.server
+	}
+
+	/// The shareable URL of a paste on this server
+	///
+	/// # Errors
+	///
+	/// Returns an error if the slug does not make a valid URL.
+	pub fn paste_url(&self, slug: &str, password: Option<&Password>) -> Result<Url> {
+		crate::paste_url(&self.server, slug, password)
+	}
+
+	/// Signs and uploads a paste, replacing an existing one with the same slug if
+	/// `overwrite` is set
+	///
+	/// The message of the body must already be signed or encrypted.
+	///
+	/// # Errors
+	///
+	/// Returns an error if signing fails or the server refuses the paste.
+	pub async fn create_paste(
+		&self,
+		body: &CreateBody,
+		overwrite: bool,
+		signer: &dyn PasteSigner,
+	) -> Result<CreateResponse> {
+		let signed_body = sign(&rmp_serde::to_vec(body)?, signer)?;
+		let method = if overwrite { Method::PUT } else { Method::POST };
+
+		let response = self
+			.http
+			.request(method, self.server.join("/api/paste")?)
+			.header(header::CONTENT_TYPE, SIGNED_CONTENT_TYPE)
+			.body(signed_body)
+			.send()
+			.await?;
+
+		match response.status() {
+			StatusCode::CREATED => decode(response).await,
+			_ => Err(error(response).await),
+		}
+	}
+
+	/// Gets a paste, or one of its past revisions
+	///
+	/// # Errors
+	///
+	/// Returns an error if the paste or revision does not exist.
+	pub async fn get_paste(&self, slug: &str, rev: Option<u32>) -> Result<ReadResponse> {
+		let response = self
+			.http
+			.get(self.server.join(&format!("/api/paste/{slug}"))?)
+			.query(&ReadQuery { rev })
+			.send()
+			.await?;
+
+		match response.status() {
+			StatusCode::OK => decode(response).await,
+			StatusCode::NOT_FOUND => Err(rev.map_or(Error::PasteNotFound, Error::RevisionNotFound)),
+			_ => Err(error(response).await),
+		}
+	}
+
+	/// Lists the revisions of a paste, the latest one last
+	///
+	/// # Errors
+	///
+	/// Returns an error if the paste does not exist.
+	pub async fn get_revisions(&self, slug: &str) -> Result<RevisionsResponse> {
+		let response = self
+			.http
+			.get(self.server.join(&format!("/api/paste/{slug}/revisions"))?)
+			.send()
+			.await?;
+
+		match response.status() {
+			StatusCode::OK => decode(response).await,
+			_ => Err(error(response).await),
+		}
+	}
+
+	/// Registers a cert with the server, the cert must be signed by itself
+	///
+	/// # Errors
+	///
+	/// Returns an error if the server refuses the cert.
+	pub async fn register_key(&self, signed_cert: Vec<u8>) -> Result<()> {
+		let response = self
+			.http
+			.post(self.server.join("/api/key")?)
+			.header(header::CONTENT_TYPE, SIGNED_CONTENT_TYPE)
+			.body(signed_cert)
+			.send()
+			.await?;
+
+		match response.status() {
+			StatusCode::CREATED => Ok(()),
+			_ => Err(error(response).await),
+		}
+	}
+
+	/// Lists the private pastes addressed to the key of the signer
+	///
+	/// # Errors
+	///
+	/// Returns an error if signing fails or the server refuses the request.
+	pub async fn inbox(&self, signer: &dyn PasteSigner) -> Result<InboxResponse> {
+		let body = InboxBody {
+			issued_at: SystemTime::now(),
+		};
+		let signed_body = sign(&rmp_serde::to_vec(&body)?, signer)?;
+
+		let response = self
+			.http
+			.get(self.server.join("/api/inbox")?)
+			.header(header::CONTENT_TYPE, SIGNED_CONTENT_TYPE)
+			.body(signed_body)
+			.send()
+			.await?;
+
+		match response.status() {
+			StatusCode::OK => decode(response).await,
+			_ => Err(error(response).await),
+		}
+	}
+}
+
+/// Decodes a `MsgPack` response body
+async fn decode<T: DeserializeOwned>(response: Response) -> Result<T> {
+	if let Some(content_type) = response.headers().get(header::CONTENT_TYPE)
+		&& content_type != mime::APPLICATION_MSGPACK.as_ref()
+	{
+		return Err(Error::InvalidContentType);
+	}
+
+	Ok(rmp_serde::from_slice(&response.bytes().await?)?)
+}
+
+/// Turns an unsuccessful response into an error
+async fn error(response: Response) -> Error {
+	let status = response.status();
+	let text = match response.text().await {
+		Ok(text) => text,
+		Err(err) => return err.into(),
+	};
+
+	match status {
+		StatusCode::NOT_FOUND => Error::PasteNotFound,
+		StatusCode::CONFLICT => Error::PasteAlreadyExists,
+		StatusCode::FORBIDDEN => Error::PasteOwnedByAnotherKey,
+		StatusCode::BAD_REQUEST => Error::BadRequest(text),
+		status => Error::Unexpected(status, text),
+	}
+}
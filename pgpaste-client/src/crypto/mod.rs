@@ -0,0 +1,12 @@
+//! Building and opening the `OpenPGP` messages of pastes
+
+use sequoia_openpgp::policy::StandardPolicy;
+
+mod receive;
+mod send;
+
+pub use receive::{GoodSignature, PasteDecryptor, Received, decrypt, open, verify};
+pub use send::{PasteSigner, encrypt, protect, sign};
+
+/// Default policy used for certificate verification
+pub const POLICY: &StandardPolicy = &StandardPolicy::new();
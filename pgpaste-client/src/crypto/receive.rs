@@ -0,0 +1,589 @@
+//! Decrypting and verifying messages
+
+use std::{io, time::SystemTime};
+
+use pgpaste_api_types::{Paste, Visibility};
+use sequoia_openpgp::{
+	Cert, Fingerprint, KeyHandle, Packet,
+	crypto::SessionKey,
+	packet::{PKESK, SKESK},
+	parse::{
+		PacketParser, PacketParserResult, Parse,
+		stream::{
+			DecryptionHelper, DecryptorBuilder, DetachedVerifierBuilder, MessageLayer,
+			MessageStructure, VerificationHelper, VerifierBuilder,
+		},
+	},
+	serialize::MarshalInto,
+	types::SymmetricAlgorithm,
+};
+
+use super::POLICY;
+use crate::{Result, StructureError};
+
+/// Provides the secrets to decrypt pastes and the certs to verify them
+pub trait PasteDecryptor {
+	/// Returns the known certs that own one of the given keys
+	///
+	/// # Errors
+	///
+	/// Returns an error if the certs cannot be looked up.
+	fn certs(&mut self, ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>>;
+
+	/// Decrypts the session key with one of the PKESKs or SKESKs and feeds it to
+	/// `decrypt` until it accepts one, returns the fingerprint of the key used if any
+	///
+	/// # Errors
+	///
+	/// Returns an error if no key nor password can decrypt the message.
+	fn decrypt(
+		&mut self,
+		pkesks: &[PKESK],
+		skesks: &[SKESK],
+		sym_algo: Option<SymmetricAlgorithm>,
+		decrypt: &mut dyn FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
+	) -> sequoia_openpgp::Result<Option<Fingerprint>>;
+}
+
+/// The content of a received message and who signed it
+#[derive(Debug)]
+pub struct Received {
+	/// The verified or decrypted content
+	pub content: Vec<u8>,
+	/// The good signatures over the content
+	pub signatures: Vec<GoodSignature>,
+	/// The key that decrypted the message, if it was not a password
+	pub decrypted_with: Option<Fingerprint>,
+}
+
+impl Received {
+	/// Whether the content carries a good signature of the given cert
+	#[must_use]
+	pub fn is_signed_by(&self, fingerprint: &Fingerprint) -> bool {
+		self.signatures
+			.iter()
+			.any(|signature| &signature.fingerprint == fingerprint)
+	}
+}
+
+/// A verified signature over the content of a message
+#[derive(Debug, Clone)]
+pub struct GoodSignature {
+	/// The cert that made the signature
+	pub fingerprint: Fingerprint,
+	/// The primary user ID of the cert, if it has a valid one
+	pub user_id: Option<String>,
+	/// When the signature was made
+	pub created_at: Option<SystemTime>,
+}
+
+/// Verifies or decrypts the inner message of a paste depending on its visibility.
+///
+/// # Errors
+///
+/// Returns an error if the message cannot be decrypted, a signature is invalid or
+/// the message does not have the shape of the paste visibility.
+pub fn open(paste: &Paste, decryptor: &mut dyn PasteDecryptor) -> Result<Received> {
+	match paste.visibility {
+		Visibility::Public => verify(&paste.inner, decryptor),
+		visibility @ (Visibility::Protected | Visibility::Private) => {
+			decrypt(&paste.inner, visibility, decryptor)
+		}
+	}
+}
+
+/// Verifies the given message, which must be a signed literal as in public pastes.
+///
+/// # Errors
+///
+/// Returns an error if a signature is invalid or the message is not a signed literal.
+pub fn verify(message: &[u8], decryptor: &mut dyn PasteDecryptor) -> Result<Received> {
+	// The verifier rejects encrypted messages as malformed, tell why instead
+	if let PacketParserResult::Some(pp) = PacketParser::from_bytes(message)?
+		&& matches!(
+			pp.packet,
+			Packet::PKESK(_) | Packet::SKESK(_) | Packet::SEIP(_)
+		) {
+		return Err(StructureError::Encrypted.into());
+	}
+
+	let mut verifier = VerifierBuilder::from_bytes(message)?.with_policy(
+		POLICY,
+		None,
+		Helper::new(decryptor, Visibility::Public),
+	)?;
+
+	let mut content = Vec::new();
+	io::copy(&mut verifier, &mut content)?;
+
+	Ok(verifier.into_helper().into_received(content))
+}
+
+/// Decrypts the given message, which must be encrypted to keys for private pastes
+/// or with a password for protected pastes.
+///
+/// Messages signed by older clients, whose signatures wrap the encrypted message
+/// rather than the literal, are still accepted.
+///
+/// # Errors
+///
+/// Returns an error if the message cannot be decrypted, a signature is invalid or
+/// the message does not have the shape of the given visibility.
+pub fn decrypt(
+	ciphertext: &[u8],
+	visibility: Visibility,
+	decryptor: &mut dyn PasteDecryptor,
+) -> Result<Received> {
+	if let Some((encrypted, signatures)) = split_legacy(ciphertext)? {
+		let mut received = decrypt(encrypted, visibility, decryptor)?;
+
+		let mut verifier = DetachedVerifierBuilder::from_bytes(signatures)?.with_policy(
+			POLICY,
+			None,
+			Helper::detached(decryptor, visibility),
+		)?;
+		verifier.verify_bytes(encrypted)?;
+		received
+			.signatures
+			.extend(verifier.into_helper().signatures);
+
+		return Ok(received);
+	}
+
+	let mut decryptor = DecryptorBuilder::from_bytes(ciphertext)?.with_policy(
+		POLICY,
+		None,
+		Helper::new(decryptor, visibility),
+	)?;
+
+	let mut content = Vec::new();
+	io::copy(&mut decryptor, &mut content)?;
+
+	Ok(decryptor.into_helper().into_received(content))
+}
+
+/// Splits a signed private or protected message written before signatures moved
+/// inside the encryption into the encrypted message and the signatures over its bytes
+///
+/// Returns `None` for messages of any other shape.
+fn split_legacy(message: &[u8]) -> Result<Option<(&[u8], &[u8])>> {
+	let mut prefix = 0;
+	let mut suffix = 0;
+	let mut encrypted = false;
+
+	let mut ppr = PacketParser::from_bytes(message)?;
+	while let PacketParserResult::Some(pp) = ppr {
+		let (packet, next) = pp.next()?;
+		match packet {
+			Packet::OnePassSig(_) if !encrypted => prefix += packet.serialized_len(),
+			Packet::PKESK(_) | Packet::SKESK(_) | Packet::SEIP(_) if prefix != 0 && suffix == 0 => {
+				encrypted = true;
+			}
+			Packet::Signature(_) if encrypted => suffix += packet.serialized_len(),
+			_ => return Ok(None),
+		}
+		ppr = next;
+	}
+
+	if suffix == 0 || prefix + suffix > message.len() {
+		return Ok(None);
+	}
+
+	let (encrypted, signatures) = message[prefix..].split_at(message.len() - prefix - suffix);
+	Ok(Some((encrypted, signatures)))
+}
+
+/// Bridges a [`PasteDecryptor`] to the sequoia streaming helpers and implements
+/// the verification policy.
+struct Helper<'a> {
+	/// The secrets and certs provider
+	decryptor: &'a mut dyn PasteDecryptor,
+	/// The visibility whose message shape is expected
+	visibility: Visibility,
+	/// Whether the signatures are checked apart from the encrypted message they cover
+	detached: bool,
+	/// The good signatures over the content
+	signatures: Vec<GoodSignature>,
+	/// The key that decrypted the message, if it was not a password
+	decrypted_with: Option<Fingerprint>,
+}
+
+impl<'a> Helper<'a> {
+	/// Wraps a provider to open a message of the given visibility
+	fn new(decryptor: &'a mut dyn PasteDecryptor, visibility: Visibility) -> Self {
+		Self {
+			decryptor,
+			visibility,
+			detached: false,
+			signatures: Vec::new(),
+			decrypted_with: None,
+		}
+	}
+
+	/// Wraps a provider to check the outer signatures of a legacy message
+	fn detached(decryptor: &'a mut dyn PasteDecryptor, visibility: Visibility) -> Self {
+		Self {
+			detached: true,
+			..Self::new(decryptor, visibility)
+		}
+	}
+
+	/// What was learned about the message
+	fn into_received(self, content: Vec<u8>) -> Received {
+		Received {
+			content,
+			signatures: self.signatures,
+			decrypted_with: self.decrypted_with,
+		}
+	}
+}
+
+impl VerificationHelper for Helper<'_> {
+	fn get_certs(&mut self, ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+		self.decryptor.certs(ids)
+	}
+
+	fn check(&mut self, structure: MessageStructure) -> sequoia_openpgp::Result<()> {
+		let mut layers = structure.into_iter();
+		let mut layer = layers.next();
+
+		// Private and protected pastes are encrypted once, around everything else
+		if self.visibility != Visibility::Public && !self.detached {
+			let Some(MessageLayer::Encryption { .. }) = layer else {
+				return Err(StructureError::NotEncrypted.into());
+			};
+			layer = layers.next();
+		}
+
+		match layer {
+			Some(MessageLayer::SignatureGroup { results }) => {
+				for result in results {
+					match result {
+						Ok(checksum) => {
+							let cert = checksum.ka.cert();
+							self.signatures.push(GoodSignature {
+								fingerprint: cert.fingerprint(),
+								user_id: cert
+									.with_policy(POLICY, None)
+									.and_then(|cert| {
+										cert.primary_userid().map(|uid| uid.userid().to_string())
+									})
+									.ok(),
+								created_at: checksum.sig.signature_creation_time(),
+							});
+						}
+						Err(e) => anyhow::bail!("signature verification failed: {}", e),
+					}
+				}
+			}
+			Some(MessageLayer::Encryption { .. }) if self.visibility == Visibility::Public => {
+				return Err(StructureError::Encrypted.into());
+			}
+			Some(layer) => return Err(StructureError::UnexpectedLayer(describe(&layer)).into()),
+			None => {}
+		}
+
+		if (self.visibility == Visibility::Public || self.detached) && self.signatures.is_empty() {
+			return Err(StructureError::Unsigned.into());
+		}
+
+		// A single literal is under the signatures
+		if let Some(layer) = layers.next() {
+			return Err(StructureError::UnexpectedLayer(describe(&layer)).into());
+		}
+
+		Ok(())
+	}
+}
+
+impl DecryptionHelper for Helper<'_> {
+	fn decrypt<D>(
+		&mut self,
+		pkesks: &[PKESK],
+		skesks: &[SKESK],
+		sym_algo: Option<SymmetricAlgorithm>,
+		mut decrypt: D,
+	) -> sequoia_openpgp::Result<Option<Fingerprint>>
+	where
+		D: FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
+	{
+		// Only the expected kind of secret may open the message
+		match self.visibility {
+			Visibility::Private if pkesks.is_empty() || !skesks.is_empty() => {
+				return Err(StructureError::NotKeyEncrypted.into());
+			}
+			Visibility::Protected if skesks.is_empty() || !pkesks.is_empty() => {
+				return Err(StructureError::NotPasswordEncrypted.into());
+			}
+			Visibility::Public => return Err(StructureError::Encrypted.into()),
+			Visibility::Private | Visibility::Protected => {}
+		}
+
+		let fingerprint = self
+			.decryptor
+			.decrypt(pkesks, skesks, sym_algo, &mut decrypt)?;
+		self.decrypted_with.clone_from(&fingerprint);
+
+		Ok(fingerprint)
+	}
+}
+
+/// The name of a message layer in errors
+const fn describe(layer: &MessageLayer) -> &'static str {
+	match layer {
+		MessageLayer::Compression { .. } => "compression",
+		MessageLayer::Encryption { .. } => "encryption",
+		MessageLayer::SignatureGroup { .. } => "signature",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+
+	use sequoia_openpgp::{
+		cert::CertBuilder,
+		crypto::{self, Password},
+		serialize::stream::{Compressor, Encryptor2, LiteralWriter, Message, Recipient, Signer},
+	};
+
+	use super::*;
+	use crate::{Error, PasteSigner, crypto::send};
+
+	/// The content of the test messages
+	const CONTENT: &[u8] = b"hello";
+
+	/// A cert with its secrets and a paste password
+	struct Keys {
+		/// The cert that signs and decrypts
+		cert: Cert,
+		/// The password of protected messages
+		password: Password,
+	}
+
+	impl Keys {
+		/// Generates a fresh cert
+		fn new() -> Result<Self> {
+			let (cert, _) =
+				CertBuilder::general_purpose(None, Some("alice <alice@example.org>")).generate()?;
+			Ok(Self {
+				cert,
+				password: "correct horse".into(),
+			})
+		}
+
+		/// The encryption keys of the cert
+		fn recipients(&self) -> Vec<Recipient<'_>> {
+			self.cert
+				.keys()
+				.with_policy(POLICY, None)
+				.for_transport_encryption()
+				.map(Recipient::from)
+				.collect()
+		}
+	}
+
+	impl PasteSigner for Keys {
+		fn signer(&self) -> sequoia_openpgp::Result<Box<dyn crypto::Signer + Send + Sync>> {
+			let key = self
+				.cert
+				.keys()
+				.secret()
+				.with_policy(POLICY, None)
+				.for_signing()
+				.next()
+				.ok_or_else(|| anyhow::anyhow!("no signing key"))?
+				.key()
+				.clone();
+			Ok(Box::new(key.into_keypair()?))
+		}
+	}
+
+	impl PasteDecryptor for Keys {
+		fn certs(&mut self, _ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+			Ok(vec![self.cert.clone()])
+		}
+
+		fn decrypt(
+			&mut self,
+			pkesks: &[PKESK],
+			skesks: &[SKESK],
+			sym_algo: Option<SymmetricAlgorithm>,
+			decrypt: &mut dyn FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
+		) -> sequoia_openpgp::Result<Option<Fingerprint>> {
+			let keys = self
+				.cert
+				.keys()
+				.secret()
+				.with_policy(POLICY, None)
+				.for_transport_encryption();
+			for ka in keys {
+				let mut keypair = ka.key().clone().into_keypair()?;
+				for pkesk in pkesks {
+					if let Some((algo, session_key)) = pkesk.decrypt(&mut keypair, sym_algo)
+						&& decrypt(algo, &session_key)
+					{
+						return Ok(Some(self.cert.fingerprint()));
+					}
+				}
+			}
+
+			for skesk in skesks {
+				if let Ok((algo, session_key)) = skesk.decrypt(&self.password)
+					&& decrypt(algo, &session_key)
+				{
+					return Ok(None);
+				}
+			}
+
+			anyhow::bail!("no key nor password decrypts the message")
+		}
+	}
+
+	/// Writes the test content as a literal under the given layers
+	fn finish(message: Message<'_>) -> Result<()> {
+		let mut literal = LiteralWriter::new(message).build()?;
+		literal.write_all(CONTENT)?;
+		literal.finalize()?;
+		Ok(())
+	}
+
+	#[test]
+	fn public_paste_is_one_signature_over_a_literal() -> Result<()> {
+		let mut keys = Keys::new()?;
+
+		let received = verify(&send::sign(CONTENT, &keys)?, &mut keys)?;
+		assert_eq!(received.content, CONTENT);
+		assert!(received.is_signed_by(&keys.cert.fingerprint()));
+
+		let mut unsigned = Vec::new();
+		finish(Message::new(&mut unsigned))?;
+		assert!(matches!(
+			verify(&unsigned, &mut keys),
+			Err(Error::Structure(StructureError::Unsigned))
+		));
+
+		let encrypted = send::encrypt(CONTENT, &[keys.cert.clone()], false, Some(&keys))?;
+		assert!(matches!(
+			verify(&encrypted, &mut keys),
+			Err(Error::Structure(StructureError::Encrypted))
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn compression_is_rejected() -> Result<()> {
+		let mut keys = Keys::new()?;
+
+		let mut public = Vec::new();
+		let message = Compressor::new(Message::new(&mut public)).build()?;
+		finish(Signer::new(message, keys.signer()?).build()?)?;
+		assert!(matches!(
+			verify(&public, &mut keys),
+			Err(Error::Structure(StructureError::UnexpectedLayer(
+				"compression"
+			)))
+		));
+
+		let mut private = Vec::new();
+		let message =
+			Encryptor2::for_recipients(Message::new(&mut private), keys.recipients()).build()?;
+		finish(Compressor::new(message).build()?)?;
+		assert!(matches!(
+			decrypt(&private, Visibility::Private, &mut keys),
+			Err(Error::Structure(StructureError::UnexpectedLayer(
+				"compression"
+			)))
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn nested_encryption_is_rejected() -> Result<()> {
+		let mut keys = Keys::new()?;
+
+		let mut nested = Vec::new();
+		let message =
+			Encryptor2::for_recipients(Message::new(&mut nested), keys.recipients()).build()?;
+		finish(Encryptor2::for_recipients(message, keys.recipients()).build()?)?;
+		assert!(matches!(
+			decrypt(&nested, Visibility::Private, &mut keys),
+			Err(Error::Structure(StructureError::UnexpectedLayer(
+				"encryption"
+			)))
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn wrong_kind_of_encryption_is_rejected() -> Result<()> {
+		let mut keys = Keys::new()?;
+
+		let protected = send::protect(CONTENT, &keys.password, Some(&keys))?;
+		assert!(matches!(
+			decrypt(&protected, Visibility::Private, &mut keys),
+			Err(Error::Structure(StructureError::NotKeyEncrypted))
+		));
+
+		let private = send::encrypt(CONTENT, &[keys.cert.clone()], false, Some(&keys))?;
+		assert!(matches!(
+			decrypt(&private, Visibility::Protected, &mut keys),
+			Err(Error::Structure(StructureError::NotPasswordEncrypted))
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn signed_pastes_open() -> Result<()> {
+		let mut keys = Keys::new()?;
+		let fingerprint = keys.cert.fingerprint();
+
+		let private = send::encrypt(CONTENT, &[keys.cert.clone()], true, Some(&keys))?;
+		let received = decrypt(&private, Visibility::Private, &mut keys)?;
+		assert_eq!(received.content, CONTENT);
+		assert!(received.is_signed_by(&fingerprint));
+		assert_eq!(received.decrypted_with, Some(fingerprint.clone()));
+
+		let protected = send::protect(CONTENT, &keys.password, Some(&keys))?;
+		let received = decrypt(&protected, Visibility::Protected, &mut keys)?;
+		assert_eq!(received.content, CONTENT);
+		assert!(received.is_signed_by(&fingerprint));
+
+		Ok(())
+	}
+
+	#[test]
+	fn legacy_signed_pastes_open() -> Result<()> {
+		let mut keys = Keys::new()?;
+		let fingerprint = keys.cert.fingerprint();
+
+		// Older clients signed the encrypted message rather than the literal
+		let mut private = Vec::new();
+		let message = Signer::new(Message::new(&mut private), keys.signer()?).build()?;
+		finish(Encryptor2::for_recipients(message, keys.recipients()).build()?)?;
+		let received = decrypt(&private, Visibility::Private, &mut keys)?;
+		assert_eq!(received.content, CONTENT);
+		assert!(received.is_signed_by(&fingerprint));
+
+		let mut protected = Vec::new();
+		let message = Signer::new(Message::new(&mut protected), keys.signer()?).build()?;
+		finish(Encryptor2::with_passwords(message, [keys.password.clone()]).build()?)?;
+		let received = decrypt(&protected, Visibility::Protected, &mut keys)?;
+		assert_eq!(received.content, CONTENT);
+		assert!(received.is_signed_by(&fingerprint));
+
+		// The outer signatures are still checked
+		let last = protected.len() - 1;
+		protected[last] ^= 1;
+		assert!(matches!(
+			decrypt(&protected, Visibility::Protected, &mut keys),
+			Err(Error::OpenPgp(_))
+		));
+
+		Ok(())
+	}
+}
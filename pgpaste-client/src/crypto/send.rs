@@ -0,0 +1,127 @@
+//! Signing and encrypting messages
+
+use std::io::Write;
+
+use sequoia_openpgp::{
+	Cert, KeyID,
+	crypto::{self, Password},
+	serialize::stream::{Encryptor2, LiteralWriter, Message, Recipient, Signer},
+};
+
+use super::POLICY;
+use crate::{Error, Result};
+
+/// Provides the key that signs pastes and requests
+pub trait PasteSigner {
+	/// Returns the keypair to make one signature with, it may ask the user to unlock it
+	///
+	/// # Errors
+	///
+	/// Returns an error if no signing key is available.
+	fn signer(&self) -> sequoia_openpgp::Result<Box<dyn crypto::Signer + Send + Sync>>;
+}
+
+/// Signs the given message.
+///
+/// # Errors
+///
+/// Returns an error if the signer fails.
+pub fn sign(content: &[u8], signer: &dyn PasteSigner) -> Result<Vec<u8>> {
+	let keypair = signer.signer()?;
+
+	let mut signed_message: Vec<u8> = Vec::new();
+	let message = Message::new(&mut signed_message);
+	let signer = Signer::new(message, keypair).build()?;
+	let mut literal = LiteralWriter::new(signer).build()?;
+
+	literal.write_all(content)?;
+	literal.finalize()?;
+
+	Ok(signed_message)
+}
+
+/// Protects the given message with a password, signing it first if a signer is given.
+///
+/// # Errors
+///
+/// Returns an error if the signer fails.
+pub fn protect(
+	content: &[u8],
+	password: &Password,
+	signer: Option<&dyn PasteSigner>,
+) -> Result<Vec<u8>> {
+	let mut protected_message: Vec<u8> = Vec::new();
+	let message = Message::new(&mut protected_message);
+
+	// Signatures go inside the encryption so only readers learn who signed
+	let encryptor = Encryptor2::with_passwords(message, [password.clone()]).build()?;
+	let next = match signer {
+		Some(signer) => Signer::new(encryptor, signer.signer()?).build()?,
+		None => encryptor,
+	};
+
+	let mut literal = LiteralWriter::new(next).build()?;
+
+	literal.write_all(content)?;
+	literal.finalize()?;
+
+	Ok(protected_message)
+}
+
+/// Encrypts the given message for all the given recipients, signing it first if a
+/// signer is given.
+///
+/// When `obscure` is set, recipients are hidden behind wildcard key IDs.
+///
+/// # Errors
+///
+/// Returns an error if a recipient has no valid encryption key or the signer fails.
+pub fn encrypt(
+	content: &[u8],
+	recipients: &[Cert],
+	obscure: bool,
+	signer: Option<&dyn PasteSigner>,
+) -> Result<Vec<u8>> {
+	let mut keys = Vec::new();
+	for cert in recipients {
+		let len = keys.len();
+
+		keys.extend(
+			cert.keys()
+				.with_policy(POLICY, None)
+				.alive()
+				.revoked(false)
+				.supported()
+				.for_transport_encryption()
+				.map(|ka| {
+					let recipient = Recipient::from(ka);
+					if obscure {
+						recipient.set_keyid(KeyID::wildcard())
+					} else {
+						recipient
+					}
+				}),
+		);
+
+		if keys.len() == len {
+			return Err(Error::NoEncryptionKey(cert.fingerprint()));
+		}
+	}
+
+	let mut encrypted_message = Vec::new();
+	let message = Message::new(&mut encrypted_message);
+
+	// Signatures go inside the encryption so only recipients learn who signed
+	let encryptor = Encryptor2::for_recipients(message, keys).build()?;
+	let next = match signer {
+		Some(signer) => Signer::new(encryptor, signer.signer()?).build()?,
+		None => encryptor,
+	};
+
+	let mut literal = LiteralWriter::new(next).build()?;
+
+	literal.write_all(content)?;
+	literal.finalize()?;
+
+	Ok(encrypted_message)
+}
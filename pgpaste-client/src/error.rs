@@ -0,0 +1,97 @@
+//! Errors returned by the client
+
+use reqwest::StatusCode;
+use sequoia_openpgp::Fingerprint;
+
+/// Result type of the client
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong when talking to a server or handling messages
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	/// The request could not be sent or its response could not be read
+	#[error("request failed: {0}")]
+	Http(#[from] reqwest::Error),
+	/// A URL could not be built
+	#[error("invalid URL: {0}")]
+	Url(#[from] url::ParseError),
+	/// The request body could not be encoded
+	#[error("could not encode the request: {0}")]
+	Encode(#[from] rmp_serde::encode::Error),
+	/// The response body could not be decoded
+	#[error("could not decode the response: {0}")]
+	Decode(#[from] rmp_serde::decode::Error),
+	/// The response is not `MsgPack`
+	#[error("Invalid content type")]
+	InvalidContentType,
+
+	/// The paste does not exist or was burnt
+	#[error("Paste not found")]
+	PasteNotFound,
+	/// The paste or the requested revision does not exist
+	#[error("Paste or revision {0} not found")]
+	RevisionNotFound(u32),
+	/// A paste with the same slug exists and overwriting was not requested
+	#[error("Paste name already exists")]
+	PasteAlreadyExists,
+	/// The paste with the same slug belongs to another key
+	#[error("Paste name is owned by another key")]
+	PasteOwnedByAnotherKey,
+	/// The server refused the request
+	#[error("{0}")]
+	BadRequest(String),
+	/// The server answered with an unexpected status
+	#[error("Unknown error: {0}, {1}")]
+	Unexpected(StatusCode, String),
+
+	/// A recipient cert cannot be encrypted to
+	#[error("cert {0} has no valid key for encryption")]
+	NoEncryptionKey(Fingerprint),
+	/// The input is neither a slug nor a paste URL
+	#[error("`{0}` is neither a slug nor a paste URL")]
+	InvalidPasteUrl(String),
+	/// The message does not have the shape its paste visibility requires
+	#[error(transparent)]
+	Structure(#[from] StructureError),
+	/// Building or parsing an `OpenPGP` message failed
+	#[error("{0}")]
+	OpenPgp(anyhow::Error),
+	/// Reading or writing a message failed
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+impl From<anyhow::Error> for Error {
+	fn from(error: anyhow::Error) -> Self {
+		// Policy violations found while parsing are reported through sequoia
+		match error.downcast::<StructureError>() {
+			Ok(error) => Self::Structure(error),
+			Err(error) => Self::OpenPgp(error),
+		}
+	}
+}
+
+/// Ways a message can differ from the shape its paste visibility requires
+///
+/// A server could otherwise serve a paste as another kind than it really is.
+#[derive(Debug, thiserror::Error)]
+pub enum StructureError {
+	/// A public message carries no signature
+	#[error("the message of a public paste must be signed")]
+	Unsigned,
+	/// A public message is encrypted
+	#[error("the message of a public paste must not be encrypted")]
+	Encrypted,
+	/// A private or protected message is not encrypted
+	#[error("the message of a private or protected paste must be encrypted")]
+	NotEncrypted,
+	/// A private message can be decrypted with a password or has no recipient
+	#[error("the message of a private paste must only be encrypted to keys")]
+	NotKeyEncrypted,
+	/// A protected message can be decrypted with a key or has no password
+	#[error("the message of a protected paste must only be encrypted with a password")]
+	NotPasswordEncrypted,
+	/// The message has a layer that paste messages never have
+	#[error("unexpected {0} layer in the message")]
+	UnexpectedLayer(&'static str),
+}
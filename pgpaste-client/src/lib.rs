@@ -0,0 +1,19 @@
+//! An async client for `pgpaste` servers
+//!
+//! The [`Client`] talks to the API while the [`crypto`] pipelines build and open the
+//! `OpenPGP` messages of the pastes. Keys are provided by implementing [`PasteSigner`]
+//! and [`PasteDecryptor`], so that callers keep control over where secrets live.
+
+mod client;
+pub mod crypto;
+mod error;
+mod url;
+
+pub use crate::{
+	client::Client,
+	crypto::{GoodSignature, PasteDecryptor, PasteSigner, Received},
+	error::{Error, Result, StructureError},
+	url::{PasteLocation, parse_paste_url, paste_url},
+};
+pub use ::url::Url;
+pub use pgpaste_api_types as api_types;
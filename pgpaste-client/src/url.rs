@@ -0,0 +1,78 @@
+//! Shareable paste URLs
+
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, percent_encode};
+use sequoia_openpgp::crypto::Password;
+use url::Url;
+
+use crate::{Error, Result};
+
+/// Where a paste lives, as given by a slug or a paste URL
+#[derive(Debug, Clone)]
+pub struct PasteLocation {
+	/// The server hosting the paste
+	pub server: Url,
+	/// The slug of the paste
+	pub slug: String,
+	/// The password found in the URL fragment, if any
+	pub password: Option<Password>,
+}
+
+/// The URL of a paste, with the password of a protected one in the fragment if given
+///
+/// Browsers never send the fragment to the server.
+///
+/// # Errors
+///
+/// Returns an error if the slug does not make a valid URL.
+pub fn paste_url(server: &Url, slug: &str, password: Option<&Password>) -> Result<Url> {
+	let mut url = server.join(&format!("/p/{slug}"))?;
+
+	// Encode anything but alphanumeric characters so the fragment decodes back exactly
+	if let Some(password) = password {
+		let fragment =
+			password.map(|password| percent_encode(password, NON_ALPHANUMERIC).to_string());
+		url.set_fragment(Some(&fragment));
+	}
+
+	Ok(url)
+}
+
+/// Split a paste URL into its server, slug and password, a bare slug is on the given server
+///
+/// # Errors
+///
+/// Returns an error if the input is a URL that does not point to a paste.
+pub fn parse_paste_url(input: &str, server: &Url) -> Result<PasteLocation> {
+	let Ok(mut url) = Url::parse(input) else {
+		return Ok(PasteLocation {
+			server: server.clone(),
+			slug: input.to_owned(),
+			password: None,
+		});
+	};
+
+	if !matches!(url.scheme(), "http" | "https") {
+		return Err(Error::InvalidPasteUrl(input.to_owned()));
+	}
+
+	let slug = url
+		.path_segments()
+		.and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+		.ok_or_else(|| Error::InvalidPasteUrl(input.to_owned()))?
+		.to_owned();
+
+	let password = url
+		.fragment()
+		.filter(|fragment| !fragment.is_empty())
+		.map(|fragment| Password::from(percent_decode_str(fragment).collect::<Vec<_>>()));
+
+	url.set_path("/");
+	url.set_query(None);
+	url.set_fragment(None);
+
+	Ok(PasteLocation {
+		server: url,
+		slug,
+		password,
+	})
+}
@@ -0,0 +1,16 @@
+create table paste_revisions
+(
+    id         serial primary key,
+    -- Revisions are burnt along with their paste
+    paste_id   int        not null
+        references pastes (id) on delete cascade,
+
+    revision   int        not null,
+    mime       text       not null,
+    visibility visibility not null,
+    content    bytea      not null,
+
+    created_at timestamp  not null,
+
+    unique (paste_id, revision)
+);
@@ -0,0 +1,4 @@
+alter table pastes
+    -- The paste this one is a reply to or a fork of
+    add column parent_id int
+        references pastes (id) on delete set null;
@@ -0,0 +1,11 @@
+create table paste_recipients
+(
+    paste_id int   not null
+        references pastes (id) on delete cascade,
+    -- Key ID of a PKESK recipient, wildcard recipients are never stored
+    key_id   bytea not null,
+
+    primary key (paste_id, key_id)
+);
+
+create index paste_recipients_key_id_idx on paste_recipients (key_id);
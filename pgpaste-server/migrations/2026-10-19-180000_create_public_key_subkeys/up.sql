@@ -0,0 +1,7 @@
+create table public_key_subkeys
+(
+    -- Fingerprint of a signing subkey, the requests it signs belong to the cert
+    fingerprint   bytea primary key,
+    public_key_id int   not null
+        references public_keys (id) on delete cascade
+);
@@ -1,6 +1,9 @@
 //! Routes handlers for creating pastes
 
-use std::time::{Duration, SystemTime};
+use std::{
+	collections::HashSet,
+	time::{Duration, SystemTime},
+};
 
 use axum::{
 	body::Bytes,
@@ -8,19 +11,28 @@ use axum::{
 	http::{HeaderMap, Method, StatusCode},
 	response::IntoResponse,
 };
+use diesel_async::{AsyncConnection, AsyncPgConnection, scoped_futures::ScopedFutureExt};
 use eyre::Context;
-use pgpaste_api_types::api::{CreateBody, CreateResponse};
-use sequoia_net::{KeyServer, Policy};
-use sequoia_openpgp::{Message, Packet, packet::Signature, parse::Parse, serialize::MarshalInto};
+use pgpaste_api_types::{
+	Visibility,
+	api::{CreateBody, CreateResponse},
+};
+use sequoia_openpgp::{Packet, serialize::MarshalInto};
 
 use crate::{
 	AppState, ToEyreError,
-	api::extract::MsgPack,
-	crypto::{SignatureHelper, verify},
+	api::{
+		extract::MsgPack,
+		signed::{SignedBody, index_subkeys, verify_signed_body},
+	},
+	crypto::validate_paste,
 	database::{
-		models::{Certificate, NewPaste, NewPublicKey, PublicKey},
+		models::{
+			NewPaste, NewPasteRecipient, NewPasteRevision, NewPublicKey, Paste, PasteRevision,
+			PublicKey,
+		},
 		prelude::*,
-		schema::{pastes, public_keys},
+		schema::{paste_recipients, pastes, public_keys},
 	},
 	error::{ServerError, UserServerError},
 };
@@ -41,62 +53,41 @@ pub(crate) async fn create_signed_paste(
 
 	// TODO: check content type
 
-	let signed_paste = Message::from_bytes(&content)
-		.to_eyre()
-		.map_err(UserServerError::InvalidCert)?;
-
-	let fingerprint = signed_paste
-		.descendants()
-		.find_map(|p| match p {
-			Packet::Signature(Signature::V4(sig)) => sig.issuer_fingerprints().next(),
-			_ => None,
-		})
-		.ok_or(UserServerError::InvalidMessageStructure)?;
-
-	let cert = if let Some(cert) = PublicKey::with_fingerprint(fingerprint)
-		.select(public_keys::cert)
-		.first::<Certificate>(&mut conn)
-		.await
-		.optional()
-		.wrap_err("could not get public key profile")?
-	{
-		cert.into()
-	} else {
-		// TODO: actually seems vulnerable, since we could be rate-limited by the keyserver
-
-		let mut key_server = KeyServer::keys_openpgp_org(Policy::Encrypted).to_eyre()?;
-
-		key_server
-			.get(fingerprint)
-			.await
-			.to_eyre()
-			.map_err(UserServerError::CertUnknown)?
-	};
-
-	let helper = SignatureHelper::new(cert.clone());
-	let bytes = verify(&content, helper).map_err(UserServerError::InvalidSignature)?;
+	let SignedBody {
+		cert,
+		content: bytes,
+	} = verify_signed_body(&mut conn, &content).await?;
 
 	let paste_query = rmp_serde::from_slice::<CreateBody>(&bytes)
 		.map_err(UserServerError::MsgPackBodyIsInvalid)?;
 
-	// TODO: return error to user
-	let content = Message::from_bytes(&paste_query.message)
-		.to_eyre()
-		.map_err(UserServerError::InvalidCert)?;
+	let content = validate_paste(&paste_query.message, paste_query.visibility, &cert)?;
 
 	let now = SystemTime::now();
 	let slug = paste_query
 		.slug
 		.unwrap_or_else(|| petname::petname(4, "-").unwrap());
-	// TODO: take into account if the user can overwrite
 	let overwrite = method == Method::PUT;
-	let burn_at = now + paste_query.burn_in.unwrap_or(WEEK);
+	let burn_at = match (paste_query.burn_in, paste_query.burn_at) {
+		(Some(_), Some(_)) => return Err(UserServerError::AmbiguousBurnDate.into()),
+		(Some(burn_in), None) => now
+			.checked_add(burn_in)
+			.ok_or(UserServerError::InvalidBurnIn)?,
+		(None, Some(burn_at)) => burn_at,
+		(None, None) => now + WEEK,
+	};
 
-	if paste_query.burn_in > Some(YEAR) {
+	// Relative and absolute lifetimes share the same bounds
+	if burn_at < now {
+		return Err(UserServerError::BurnDateInPast.into());
+	}
+	if burn_at > now + YEAR {
 		return Err(UserServerError::InvalidBurnIn.into());
 	}
 
-	let id = if let Some(id) = PublicKey::with_fingerprint(fingerprint)
+	// Rows are keyed by the primary fingerprint, the body may be signed by a subkey
+	let fingerprint = cert.fingerprint();
+	let id = if let Some(id) = PublicKey::with_fingerprint(&fingerprint)
 		.select(public_keys::id)
 		.first::<i32>(&mut conn)
 		.await
@@ -120,9 +111,25 @@ pub(crate) async fn create_signed_paste(
 			.await
 			.wrap_err("could not insert new public key")?
 	};
+	index_subkeys(&mut conn, id, &cert).await?;
+
+	let parent_id = match &paste_query.parent {
+		Some(parent) if parent == &slug => return Err(UserServerError::InvalidParent.into()),
+		Some(parent) => Some(
+			Paste::with_slug(parent)
+				.select(pastes::id)
+				.first::<i32>(&mut conn)
+				.await
+				.optional()
+				.wrap_err("could not get parent paste")?
+				.ok_or(UserServerError::InvalidParent)?,
+		),
+		None => None,
+	};
 
 	let paste = NewPaste {
 		public_key_id: id,
+		parent_id,
 		slug: &slug,
 		mime: (&paste_query.mime).into(),
 		visibility: &(&paste_query.visibility).into(),
@@ -132,26 +139,86 @@ pub(crate) async fn create_signed_paste(
 		burn_after_read: paste_query.burn_after_read,
 	};
 
-	// TODO: find a way to box insert queries
-	// TODO: handle conflict errors
-	if overwrite {
-		paste
-			.insert()
-			.on_conflict(pastes::id)
-			.do_update()
-			.set(&paste)
-			.execute(&mut conn)
-			.await
-			.wrap_err("Could not insert paste")?;
+	// Recipients of private pastes are indexed for their inbox, wildcards stay hidden
+	let recipients = if paste_query.visibility == Visibility::Private {
+		content
+			.descendants()
+			.filter_map(|packet| match packet {
+				Packet::PKESK(pkesk) if !pkesk.recipient().is_wildcard() => {
+					Some(pkesk.recipient().as_bytes().to_vec())
+				}
+				_ => None,
+			})
+			.collect::<HashSet<_>>()
 	} else {
-		paste
-			.insert()
-			.on_conflict(pastes::id)
-			.do_nothing()
-			.execute(&mut conn)
-			.await
-			.wrap_err("Could not insert paste")?;
-	}
+		HashSet::new()
+	};
+
+	conn.transaction::<_, ServerError, _>(|conn| {
+		let (slug, paste, recipients) = (slug.as_str(), &paste, &recipients);
+
+		async move {
+			let existing = Paste::with_slug_including_burnt(slug)
+				.select(Paste::as_select())
+				.for_update()
+				.first::<Paste>(conn)
+				.await
+				.optional()
+				.wrap_err("could not get existing paste")?;
+
+			let paste_id = match existing {
+				// Burnt pastes are only waiting for the cleanup routine, the slug is free
+				Some(old) if old.burn_at <= now => {
+					db_dsl::delete(pastes::table.find(old.id))
+						.execute(conn)
+						.await
+						.wrap_err("could not delete burnt paste")?;
+
+					insert_paste(paste, conn).await?
+				}
+				Some(_) if !overwrite => return Err(UserServerError::PasteAlreadyExists.into()),
+				Some(old) if old.public_key_id != id => {
+					return Err(UserServerError::PasteNotOwned.into());
+				}
+				Some(old) => {
+					archive_paste(&old, conn).await?;
+
+					db_dsl::update(pastes::table.find(old.id))
+						.set(paste)
+						.execute(conn)
+						.await
+						.wrap_err("could not overwrite paste")?;
+
+					db_dsl::delete(
+						paste_recipients::table.filter(paste_recipients::paste_id.eq(old.id)),
+					)
+					.execute(conn)
+					.await
+					.wrap_err("could not remove previous paste recipients")?;
+
+					old.id
+				}
+				None => insert_paste(paste, conn).await?,
+			};
+
+			let recipients = recipients
+				.iter()
+				.map(|key_id| NewPasteRecipient { paste_id, key_id })
+				.collect::<Vec<_>>();
+
+			if !recipients.is_empty() {
+				db_dsl::insert_into(paste_recipients::table)
+					.values(&recipients)
+					.execute(conn)
+					.await
+					.wrap_err("could not index paste recipients")?;
+			}
+
+			Ok(())
+		}
+		.scope_boxed()
+	})
+	.await?;
 
 	tracing::debug!(slug = slug, "Created {:?} paste", paste.visibility);
 
@@ -160,3 +227,44 @@ pub(crate) async fn create_signed_paste(
 		MsgPack(CreateResponse { slug, burn_at }),
 	))
 }
+
+/// Insert a new paste and return its id
+async fn insert_paste(
+	paste: &NewPaste<'_>,
+	conn: &mut AsyncPgConnection,
+) -> Result<i32, ServerError> {
+	let id = paste
+		.insert()
+		.returning(pastes::id)
+		.get_result(conn)
+		.await
+		.wrap_err("could not insert paste")?;
+
+	Ok(id)
+}
+
+/// Keep the current content of a paste as its latest revision before it is overwritten
+async fn archive_paste(paste: &Paste<'_>, conn: &mut AsyncPgConnection) -> Result<(), ServerError> {
+	let archived = PasteRevision::of_paste(paste.id)
+		.count()
+		.get_result::<i64>(conn)
+		.await
+		.wrap_err("could not count paste revisions")?;
+
+	let revision = NewPasteRevision {
+		paste_id: paste.id,
+		revision: i32::try_from(archived + 1).wrap_err("too many paste revisions")?,
+		mime: &paste.mime,
+		visibility: &paste.visibility,
+		content: &paste.content,
+		created_at: &paste.created_at,
+	};
+
+	revision
+		.insert()
+		.execute(conn)
+		.await
+		.wrap_err("could not archive paste revision")?;
+
+	Ok(())
+}
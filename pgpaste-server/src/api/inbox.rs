@@ -0,0 +1,80 @@
+//! Routes handlers for listing the private pastes addressed to a key
+
+use std::time::{Duration, SystemTime};
+
+use axum::{body::Bytes, extract::State, http::StatusCode, response::IntoResponse};
+use eyre::Context;
+use pgpaste_api_types::{
+	InboxEntry,
+	api::{InboxBody, InboxResponse},
+};
+use sequoia_openpgp::Fingerprint;
+
+use crate::{
+	AppState,
+	api::{
+		extract::MsgPack,
+		signed::{SignedBody, verify_signed_body},
+	},
+	database::{
+		models::{Paste, Visibility},
+		prelude::*,
+		schema::{paste_recipients, pastes, public_keys},
+	},
+	error::{ServerError, UserServerError},
+};
+
+/// How long a signed inbox request stays valid, to limit replays
+const MAX_REQUEST_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[tracing::instrument(skip(state, content))]
+pub(crate) async fn get_inbox(
+	State(state): State<AppState>,
+	content: Bytes,
+) -> Result<impl IntoResponse, ServerError> {
+	let mut conn = state.database.get().await?;
+
+	let SignedBody { cert, content } = verify_signed_body(&mut conn, &content).await?;
+
+	let inbox_query = rmp_serde::from_slice::<InboxBody>(&content)
+		.map_err(UserServerError::MsgPackBodyIsInvalid)?;
+
+	let age = SystemTime::now()
+		.duration_since(inbox_query.issued_at)
+		.unwrap_or_else(|err| err.duration());
+	if age > MAX_REQUEST_AGE {
+		return Err(UserServerError::StaleRequest.into());
+	}
+
+	let key_ids = cert
+		.keys()
+		.map(|ka| ka.key().keyid().as_bytes().to_vec())
+		.collect::<Vec<_>>();
+
+	let addressed = paste_recipients::table
+		.filter(paste_recipients::key_id.eq_any(&key_ids))
+		.select(paste_recipients::paste_id);
+
+	let pastes = Paste::all_valid()
+		.filter(pastes::visibility.eq(Visibility::Private))
+		.filter(pastes::id.eq_any(addressed))
+		.inner_join(public_keys::table)
+		.select((Paste::as_select(), public_keys::fingerprint))
+		.order(pastes::created_at.desc())
+		.load::<(Paste, Vec<u8>)>(&mut conn)
+		.await
+		.wrap_err("Failed to load inbox pastes")?;
+
+	let res: InboxResponse = pastes
+		.into_iter()
+		.map(|(paste, signer)| InboxEntry {
+			slug: paste.slug,
+			mime: paste.mime.into(),
+			signer: Fingerprint::from_bytes(&signer).to_hex(),
+			created_at: paste.created_at,
+			burn_at: paste.burn_at,
+		})
+		.collect();
+
+	Ok((StatusCode::OK, MsgPack(res)))
+}
@@ -0,0 +1,86 @@
+//! Routes handlers for registering public keys
+
+use axum::{body::Bytes, extract::State, http::StatusCode, response::IntoResponse};
+use diesel::upsert::excluded;
+use eyre::{Context, ContextCompat};
+use sequoia_openpgp::{Cert, Message, parse::Parse};
+
+use crate::{
+	AppState, ToEyreError,
+	api::signed::{index_subkeys, signer_fingerprint},
+	crypto::{SignatureHelper, verify},
+	database::{
+		models::{Certificate, NewPublicKey, PublicKey},
+		prelude::*,
+		schema::public_keys,
+	},
+	error::{ServerError, UserServerError},
+};
+
+/// Register a certificate that signed itself, so that it does not need to be
+/// known by the keyserver
+#[tracing::instrument(skip(state, content))]
+pub(crate) async fn register_key(
+	State(state): State<AppState>,
+	content: Bytes,
+) -> Result<impl IntoResponse, ServerError> {
+	let mut conn = state.database.get().await?;
+
+	let message = Message::from_bytes(&content)
+		.to_eyre()
+		.map_err(UserServerError::InvalidCert)?;
+
+	let fingerprint = signer_fingerprint(&message)?;
+
+	let cert = message
+		.body()
+		.wrap_err("no literal data")
+		.and_then(|literal| Cert::from_bytes(literal.body()).to_eyre())
+		.map_err(UserServerError::InvalidCert)?
+		.strip_secret_key_material();
+
+	// The signature proves that the sender holds the secret key of the cert
+	if !cert.keys().any(|ka| ka.key().fingerprint() == fingerprint) {
+		return Err(UserServerError::InvalidMessageStructure.into());
+	}
+	verify(&content, SignatureHelper::new(cert.clone()))
+		.map_err(UserServerError::InvalidSignature)?;
+
+	// Keep what is already known, an older copy must not drop revocations or expiry updates
+	let stored = PublicKey::with_fingerprint(&cert.fingerprint())
+		.select(public_keys::cert)
+		.first::<Certificate>(&mut conn)
+		.await
+		.optional()
+		.wrap_err("could not get public key profile")?;
+	let cert = match stored {
+		Some(stored) => Cert::from(stored)
+			.merge_public(cert)
+			.to_eyre()
+			.map_err(UserServerError::InvalidCert)?,
+		None => cert,
+	};
+
+	// One row per cert, even when a subkey signed the request
+	let primary_fingerprint = cert.fingerprint();
+	let new_pub_key = NewPublicKey {
+		fingerprint: primary_fingerprint.as_bytes(),
+		cert: (&cert).into(),
+		is_premium: false,
+	};
+
+	let id = new_pub_key
+		.insert()
+		.on_conflict(public_keys::fingerprint)
+		.do_update()
+		.set(public_keys::cert.eq(excluded(public_keys::cert)))
+		.returning(public_keys::id)
+		.get_result::<i32>(&mut conn)
+		.await
+		.wrap_err("could not register public key")?;
+	index_subkeys(&mut conn, id, &cert).await?;
+
+	tracing::debug!(fingerprint = %primary_fingerprint, "Registered public key");
+
+	Ok(StatusCode::CREATED)
+}
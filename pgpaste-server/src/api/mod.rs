@@ -9,7 +9,10 @@ use axum::{
 use crate::AppState;
 
 mod create;
+mod inbox;
+mod key;
 mod read;
+mod signed;
 
 /// The API routes definition
 pub(crate) fn api_router() -> Router<AppState> {
@@ -19,12 +22,15 @@ pub(crate) fn api_router() -> Router<AppState> {
 			post(create::create_signed_paste).put(create::create_signed_paste),
 		)
 		.route(
-			"/paste/:slug",
+			"/paste/{slug}",
 			get(read::get_paste).delete(|| async { StatusCode::NOT_IMPLEMENTED }),
 		)
-		.route("/key/:fingerprint/list", get(read::get_key_pastes))
+		.route("/paste/{slug}/revisions", get(read::get_paste_revisions))
+		.route("/key", post(key::register_key))
+		.route("/key/{fingerprint}/list", get(read::get_key_pastes))
+		.route("/inbox", get(inbox::get_inbox))
 		// Set the limit to the default 2 MiB
-		.layer(DefaultBodyLimit::max(2 * 1024))
+		.layer(DefaultBodyLimit::max(2 * 1024 * 1024))
 }
 
 /// Custom axum extractors
@@ -156,6 +162,7 @@ mod extract {
 	// ----------------------------------------------------------------------
 
 	/// Axum extractor for `OpenPGP` messages
+	#[allow(dead_code)]
 	pub struct PgpMessage(pub Message);
 
 	impl<S> FromRequest<S> for PgpMessage
@@ -177,6 +184,7 @@ mod extract {
 	}
 
 	/// Whether the request has a msgpack content type
+	#[allow(dead_code)]
 	fn has_pgp_signature_content_type(headers: &HeaderMap) -> bool {
 		let Some(content_type) = headers.get(header::CONTENT_TYPE) else {
 			return false;
@@ -195,6 +203,7 @@ mod extract {
 	///
 	/// Contains one variant for each way the [`MsgPack`] extractor
 	/// can fail.
+	#[allow(dead_code)]
 	#[non_exhaustive]
 	#[derive(Debug, thiserror::Error)]
 	pub enum PgpMessageRejection {
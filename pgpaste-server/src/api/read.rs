@@ -1,19 +1,25 @@
 //! Routes handlers for reading pastes
 
-use std::time::SystemTime;
-
 use axum::{
-	extract::{Path, State},
+	extract::{Path, Query, State},
 	http::StatusCode,
 	response::IntoResponse,
 };
 use eyre::Context;
-use pgpaste_api_types::api::ReadResponse;
+use pgpaste_api_types::{
+	Revision,
+	api::{ReadQuery, ReadResponse, RevisionsResponse},
+};
+use sequoia_openpgp::Fingerprint;
 
 use super::extract::MsgPack;
 use crate::{
 	AppState,
-	database::{models::Paste, prelude::*},
+	database::{
+		models::{Paste, PasteRevision, PublicKey},
+		prelude::*,
+		schema::{paste_revisions, public_keys},
+	},
 	error::{ServerError, UserServerError},
 };
 
@@ -21,6 +27,7 @@ use crate::{
 pub(crate) async fn get_paste(
 	State(state): State<AppState>,
 	Path(paste_slug): Path<String>,
+	Query(query): Query<ReadQuery>,
 ) -> Result<impl IntoResponse, ServerError> {
 	let mut conn = state.database.get().await?;
 
@@ -34,14 +41,105 @@ pub(crate) async fn get_paste(
 		return Err(UserServerError::PasteNotFound.into());
 	};
 
-	let res = ReadResponse {
+	let archived = PasteRevision::of_paste(paste.id)
+		.count()
+		.get_result::<i64>(&mut conn)
+		.await
+		.wrap_err("Failed to count paste revisions")?;
+
+	let (parent, children) = paste
+		.thread(&mut conn)
+		.await
+		.wrap_err("Failed to load paste thread")?;
+
+	let signer = PublicKey::with_id(paste.public_key_id)
+		.select(public_keys::fingerprint)
+		.first::<Vec<u8>>(&mut conn)
+		.await
+		.wrap_err("Failed to load paste signer")?;
+
+	let mut res = ReadResponse {
 		slug: paste.slug,
 		mime: paste.mime.into(),
 		visibility: (&paste.visibility).into(),
+		burn_at: paste.burn_at,
+		signer: Fingerprint::from_bytes(&signer).to_hex(),
+		parent,
+		children,
 		inner: paste.content,
-		burn_at: SystemTime::now(),
+		burn_after_read: paste.burn_after_read,
 	};
 
+	// The latest revision is the paste itself
+	if let Some(rev) = query.rev
+		&& i64::from(rev) != archived + 1
+	{
+		let revision = i32::try_from(rev).map_err(|_| UserServerError::RevisionNotFound)?;
+
+		let Some(revision) = PasteRevision::with_revision(paste.id, revision)
+			.select(PasteRevision::as_select())
+			.first::<PasteRevision>(&mut conn)
+			.await
+			.optional()
+			.wrap_err("Failed to load paste revision")?
+		else {
+			return Err(UserServerError::RevisionNotFound.into());
+		};
+
+		res.mime = revision.mime.into();
+		res.visibility = (&revision.visibility).into();
+		res.inner = revision.content;
+	}
+
+	Ok((StatusCode::OK, MsgPack(res)))
+}
+
+#[tracing::instrument(skip(state))]
+pub(crate) async fn get_paste_revisions(
+	State(state): State<AppState>,
+	Path(paste_slug): Path<String>,
+) -> Result<impl IntoResponse, ServerError> {
+	let mut conn = state.database.get().await?;
+
+	let Some(paste) = Paste::with_slug(&paste_slug)
+		.select(Paste::as_select())
+		.first::<Paste>(&mut conn)
+		.await
+		.optional()
+		.wrap_err("Failed to load paste")?
+	else {
+		return Err(UserServerError::PasteNotFound.into());
+	};
+
+	let archived = PasteRevision::of_paste(paste.id)
+		.select(PasteRevision::as_select())
+		.order(paste_revisions::revision.asc())
+		.load::<PasteRevision>(&mut conn)
+		.await
+		.wrap_err("Failed to load paste revisions")?;
+
+	let latest = u32::try_from(archived.len() + 1).wrap_err("Too many paste revisions")?;
+
+	let mut res = archived
+		.into_iter()
+		.map(|revision| {
+			Ok(Revision {
+				revision: u32::try_from(revision.revision)
+					.wrap_err("Invalid paste revision number")?,
+				mime: revision.mime.into(),
+				visibility: (&revision.visibility).into(),
+				created_at: revision.created_at,
+			})
+		})
+		.collect::<eyre::Result<RevisionsResponse>>()?;
+
+	res.push(Revision {
+		revision: latest,
+		mime: paste.mime.into(),
+		visibility: (&paste.visibility).into(),
+		created_at: paste.created_at,
+	});
+
 	Ok((StatusCode::OK, MsgPack(res)))
 }
 
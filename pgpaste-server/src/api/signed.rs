@@ -0,0 +1,123 @@
+//! Verification of the signed bodies sent to authenticated endpoints
+
+use diesel_async::AsyncPgConnection;
+use eyre::Context;
+use sequoia_net::{KeyServer, Policy};
+use sequoia_openpgp::{Cert, Fingerprint, Message, Packet, packet::Signature, parse::Parse};
+
+use crate::{
+	ToEyreError,
+	crypto::{SignatureHelper, signing_subkeys, verify},
+	database::{
+		models::{Certificate, NewPublicKeySubkey, PublicKey},
+		prelude::*,
+		schema::public_keys,
+	},
+	error::{ServerError, UserServerError},
+};
+
+/// A request body whose signature was verified
+pub(crate) struct SignedBody {
+	/// Certificate of the key that signed the body
+	pub(crate) cert: Cert,
+	/// The verified inner content
+	pub(crate) content: Vec<u8>,
+}
+
+/// Find the fingerprint of the key that signed the given message
+pub(crate) fn signer_fingerprint(message: &Message) -> Result<Fingerprint, UserServerError> {
+	message
+		.descendants()
+		.find_map(|p| match p {
+			Packet::Signature(Signature::V4(sig)) => sig.issuer_fingerprints().next(),
+			_ => None,
+		})
+		.cloned()
+		.ok_or(UserServerError::InvalidMessageStructure)
+}
+
+/// Find the registered cert owning the given key, the rows are keyed by primary fingerprint
+async fn registered_cert(
+	conn: &mut AsyncPgConnection,
+	fingerprint: &Fingerprint,
+) -> eyre::Result<Option<Cert>> {
+	let cert = PublicKey::with_fingerprint(fingerprint)
+		.select(public_keys::cert)
+		.first::<Certificate>(conn)
+		.await
+		.optional()
+		.wrap_err("could not get public key profile")?;
+	if let Some(cert) = cert {
+		return Ok(Some(cert.into()));
+	}
+
+	// A subkey signed the body, its fingerprint is not the one of the row
+	let cert = PublicKey::with_subkey(fingerprint)
+		.select(public_keys::cert)
+		.first::<Certificate>(conn)
+		.await
+		.optional()
+		.wrap_err("could not get public key profile by subkey")?;
+
+	Ok(cert.map(Cert::from))
+}
+
+/// Index the signing subkeys of a registered cert, so that the requests they sign
+/// find its row
+pub(crate) async fn index_subkeys(
+	conn: &mut AsyncPgConnection,
+	public_key_id: i32,
+	cert: &Cert,
+) -> eyre::Result<()> {
+	let fingerprints = signing_subkeys(cert);
+	if fingerprints.is_empty() {
+		return Ok(());
+	}
+
+	let subkeys = fingerprints
+		.iter()
+		.map(|fingerprint| NewPublicKeySubkey {
+			fingerprint: fingerprint.as_bytes(),
+			public_key_id,
+		})
+		.collect::<Vec<_>>();
+
+	NewPublicKeySubkey::insert_all(&subkeys)
+		.on_conflict_do_nothing()
+		.execute(conn)
+		.await
+		.wrap_err("could not index subkeys")?;
+
+	Ok(())
+}
+
+/// Find the signer of the given message, fetch its certificate and verify the signature
+pub(crate) async fn verify_signed_body(
+	conn: &mut AsyncPgConnection,
+	content: &[u8],
+) -> Result<SignedBody, ServerError> {
+	let signed_message = Message::from_bytes(content)
+		.to_eyre()
+		.map_err(UserServerError::InvalidCert)?;
+
+	let fingerprint = signer_fingerprint(&signed_message)?;
+
+	let cert = if let Some(cert) = registered_cert(conn, &fingerprint).await? {
+		cert
+	} else {
+		// TODO: actually seems vulnerable, since we could be rate-limited by the keyserver
+
+		let mut key_server = KeyServer::keys_openpgp_org(Policy::Encrypted).to_eyre()?;
+
+		key_server
+			.get(&fingerprint)
+			.await
+			.to_eyre()
+			.map_err(UserServerError::CertUnknown)?
+	};
+
+	let helper = SignatureHelper::new(cert.clone());
+	let content = verify(content, helper).map_err(UserServerError::InvalidSignature)?;
+
+	Ok(SignedBody { cert, content })
+}
@@ -2,16 +2,22 @@
 
 use std::io;
 
+use pgpaste_api_types::Visibility;
 use sequoia_openpgp::{
-	Cert, KeyHandle,
+	Cert, Fingerprint, KeyHandle, Message, Packet,
+	packet::Signature,
 	parse::{
 		Parse,
-		stream::{MessageLayer, MessageStructure, VerificationHelper, VerifierBuilder},
+		stream::{
+			DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationHelper,
+			VerifierBuilder,
+		},
 	},
 	policy::StandardPolicy,
+	serialize::MarshalInto,
 };
 
-use crate::ToEyreError;
+use crate::{ToEyreError, error::UserServerError};
 
 /// Default policy used for certificate verification
 const POLICY: &StandardPolicy = &StandardPolicy::new();
@@ -29,9 +35,138 @@ pub(crate) fn verify(message: &[u8], helper: SignatureHelper) -> eyre::Result<Ve
 	Ok(bytes)
 }
 
-/// This helper provides secrets for the decryption, fetches public
-/// keys for the signature verification and implements the
-/// verification policy.
+/// Fingerprints of the signing subkeys of the cert, under which it is indexed
+///
+/// The policy only accepts signing subkeys with a back signature, so no cert can
+/// claim the subkey of another.
+pub(crate) fn signing_subkeys(cert: &Cert) -> Vec<Fingerprint> {
+	cert.keys()
+		.subkeys()
+		.with_policy(POLICY, None)
+		.for_signing()
+		.map(|ka| ka.key().fingerprint())
+		.collect()
+}
+
+/// Verifies the given detached signatures over `data`
+pub(crate) fn verify_detached(
+	data: &[u8],
+	signatures: &[u8],
+	helper: SignatureHelper,
+) -> eyre::Result<()> {
+	DetachedVerifierBuilder::from_bytes(signatures)
+		.to_eyre()?
+		.with_policy(POLICY, None, helper)
+		.to_eyre()?
+		.verify_bytes(data)
+		.to_eyre()
+}
+
+/// Parses the message of a paste and checks it has the shape its visibility requires
+///
+/// The server cannot decrypt pastes, so only the packets outside of the encryption
+/// are checked. The content of a public paste must be signed by the given cert, the
+/// one that signed the request. So must the signatures older clients wrapped around
+/// the encrypted message of private and protected pastes.
+pub(crate) fn validate_paste(
+	bytes: &[u8],
+	visibility: Visibility,
+	cert: &Cert,
+) -> Result<Message, UserServerError> {
+	let message = Message::from_bytes(bytes)
+		.to_eyre()
+		.map_err(UserServerError::InvalidPasteMessage)?;
+
+	match visibility {
+		Visibility::Public => {
+			let mut signed = false;
+			for packet in message.children() {
+				match packet {
+					Packet::OnePassSig(_) | Packet::Literal(_) => {}
+					Packet::Signature(sig) => {
+						check_issuer(sig, cert)?;
+						signed = true;
+					}
+					_ => return Err(UserServerError::PublicPasteNotLiteral),
+				}
+			}
+
+			if !signed {
+				return Err(UserServerError::PublicPasteNotSigned);
+			}
+
+			verify(bytes, SignatureHelper::new(cert.clone()))
+				.map_err(UserServerError::InvalidSignature)?;
+		}
+		Visibility::Private | Visibility::Protected => {
+			let (mut pkesks, mut skesks) = (0, 0);
+			// Lengths of the legacy signature packets around the encrypted message
+			let (mut prefix, mut suffix) = (0, 0);
+			let mut encrypted = false;
+			for packet in message.children() {
+				match packet {
+					Packet::OnePassSig(_) if pkesks + skesks == 0 && !encrypted => {
+						prefix += packet.serialized_len();
+					}
+					Packet::PKESK(_) if !encrypted => pkesks += 1,
+					Packet::SKESK(_) if !encrypted => skesks += 1,
+					Packet::SEIP(_) | Packet::AED(_) if !encrypted => encrypted = true,
+					Packet::Signature(sig) if encrypted && prefix != 0 => {
+						check_issuer(sig, cert)?;
+						suffix += packet.serialized_len();
+					}
+					_ => return Err(UserServerError::PlaintextOutsideEncryption),
+				}
+			}
+
+			match visibility {
+				Visibility::Private if pkesks == 0 => {
+					return Err(UserServerError::PrivatePasteWithoutRecipients);
+				}
+				Visibility::Private if skesks > 0 => {
+					return Err(UserServerError::PrivatePasteWithPassword);
+				}
+				Visibility::Protected if skesks == 0 => {
+					return Err(UserServerError::ProtectedPasteWithoutPassword);
+				}
+				Visibility::Protected if pkesks > 0 => {
+					return Err(UserServerError::ProtectedPasteWithRecipients);
+				}
+				_ => {}
+			}
+
+			if suffix != 0 {
+				let (encrypted, signatures) = bytes
+					.get(prefix..)
+					.and_then(|rest| rest.split_at_checked(rest.len().checked_sub(suffix)?))
+					.ok_or(UserServerError::InvalidMessageStructure)?;
+				verify_detached(encrypted, signatures, SignatureHelper::new(cert.clone()))
+					.map_err(UserServerError::InvalidSignature)?;
+			}
+		}
+	}
+
+	Ok(message)
+}
+
+/// Checks that the given signature claims to be made by one of the keys of `cert`
+fn check_issuer(sig: &Signature, cert: &Cert) -> Result<(), UserServerError> {
+	if sig.get_issuers().iter().any(|issuer| owns(cert, issuer)) {
+		Ok(())
+	} else {
+		Err(UserServerError::PasteSignerMismatch)
+	}
+}
+
+/// Whether one of the keys of the cert has the given handle, signatures are
+/// often made by a subkey rather than the primary key
+fn owns(cert: &Cert, handle: &KeyHandle) -> bool {
+	cert.keys().any(|ka| handle.aliases(ka.key().key_handle()))
+}
+
+/// This helper fetches public keys for the signature verification and
+/// implements the verification policy: one group of good signatures over
+/// a literal or detached data.
 pub(crate) struct SignatureHelper {
 	/// The certificate used for verification.
 	cert: Cert,
@@ -46,31 +181,275 @@ impl SignatureHelper {
 
 impl VerificationHelper for SignatureHelper {
 	fn get_certs(&mut self, ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
-		if ids
-			.iter()
-			.any(|handle| handle == &self.cert.fingerprint().into())
-		{
+		if ids.iter().any(|handle| owns(&self.cert, handle)) {
 			Ok(vec![self.cert.clone()])
 		} else {
 			Ok(vec![])
 		}
 	}
 
-	// TODO: implement message structure verification policy
 	fn check(&mut self, structure: MessageStructure) -> sequoia_openpgp::Result<()> {
-		for layer in structure.iter() {
-			match layer {
-				MessageLayer::Compression { .. } | MessageLayer::Encryption { .. } => {}
-				MessageLayer::SignatureGroup { results } => {
-					for result in results {
-						if let Err(e) = result {
-							anyhow::bail!("signature verification failed: {}", e)
-						}
-					}
-				}
+		let mut layers = structure.into_iter();
+
+		let Some(MessageLayer::SignatureGroup { results }) = layers.next() else {
+			anyhow::bail!("message is not signed");
+		};
+		if results.is_empty() {
+			anyhow::bail!("message is not signed");
+		}
+		for result in results {
+			if let Err(e) = result {
+				anyhow::bail!("signature verification failed: {}", e)
 			}
 		}
 
+		if layers.next().is_some() {
+			anyhow::bail!("message has unexpected layers");
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+
+	use sequoia_openpgp::{
+		cert::CertBuilder,
+		crypto::KeyPair,
+		serialize::stream::{self, Compressor, Encryptor2, LiteralWriter, Recipient, Signer},
+	};
+
+	use super::*;
+
+	/// The password of the test messages
+	const PASSWORD: &str = "correct horse";
+
+	/// Generates a fresh cert
+	fn cert() -> sequoia_openpgp::Result<Cert> {
+		let (cert, _) =
+			CertBuilder::general_purpose(None, Some("alice <alice@example.org>")).generate()?;
+		Ok(cert)
+	}
+
+	/// The signing keypair of the cert
+	fn signer(cert: &Cert) -> sequoia_openpgp::Result<KeyPair> {
+		let ka = cert
+			.keys()
+			.secret()
+			.with_policy(POLICY, None)
+			.for_signing()
+			.next()
+			.ok_or_else(|| anyhow::anyhow!("no signing key"))?;
+		ka.key().clone().into_keypair()
+	}
+
+	/// The encryption keys of the cert
+	fn recipients(cert: &Cert) -> Vec<Recipient<'_>> {
+		cert.keys()
+			.with_policy(POLICY, None)
+			.for_transport_encryption()
+			.map(Recipient::from)
+			.collect()
+	}
+
+	/// Writes a literal under the given layers
+	fn finish(message: stream::Message<'_>) -> sequoia_openpgp::Result<()> {
+		let mut literal = LiteralWriter::new(message).build()?;
+		literal.write_all(b"hello")?;
+		literal.finalize()
+	}
+
+	#[test]
+	fn well_formed_pastes_are_accepted() -> sequoia_openpgp::Result<()> {
+		let cert = cert()?;
+
+		let mut public = Vec::new();
+		finish(Signer::new(stream::Message::new(&mut public), signer(&cert)?).build()?)?;
+		assert!(validate_paste(&public, Visibility::Public, &cert).is_ok());
+
+		let mut private = Vec::new();
+		let message =
+			Encryptor2::for_recipients(stream::Message::new(&mut private), recipients(&cert))
+				.build()?;
+		finish(Signer::new(message, signer(&cert)?).build()?)?;
+		assert!(validate_paste(&private, Visibility::Private, &cert).is_ok());
+
+		let mut protected = Vec::new();
+		finish(
+			Encryptor2::with_passwords(stream::Message::new(&mut protected), [PASSWORD]).build()?,
+		)?;
+		assert!(validate_paste(&protected, Visibility::Protected, &cert).is_ok());
+
+		Ok(())
+	}
+
+	#[test]
+	fn legacy_signed_pastes_are_accepted() -> sequoia_openpgp::Result<()> {
+		let cert = cert()?;
+
+		// Older clients signed the encrypted message rather than the literal
+		let mut private = Vec::new();
+		let message = Signer::new(stream::Message::new(&mut private), signer(&cert)?).build()?;
+		finish(Encryptor2::for_recipients(message, recipients(&cert)).build()?)?;
+		assert!(validate_paste(&private, Visibility::Private, &cert).is_ok());
+
+		let mut protected = Vec::new();
+		let message = Signer::new(stream::Message::new(&mut protected), signer(&cert)?).build()?;
+		finish(Encryptor2::with_passwords(message, [PASSWORD]).build()?)?;
+		assert!(validate_paste(&protected, Visibility::Protected, &cert).is_ok());
+
+		let last = protected.len() - 1;
+		protected[last] ^= 1;
+		assert!(matches!(
+			validate_paste(&protected, Visibility::Protected, &cert),
+			Err(UserServerError::InvalidSignature(_))
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn invalid_paste_message() -> sequoia_openpgp::Result<()> {
+		assert!(matches!(
+			validate_paste(b"not a message", Visibility::Public, &cert()?),
+			Err(UserServerError::InvalidPasteMessage(_))
+		));
+		Ok(())
+	}
+
+	#[test]
+	fn public_paste_not_signed() -> sequoia_openpgp::Result<()> {
+		let mut unsigned = Vec::new();
+		finish(stream::Message::new(&mut unsigned))?;
+		assert!(matches!(
+			validate_paste(&unsigned, Visibility::Public, &cert()?),
+			Err(UserServerError::PublicPasteNotSigned)
+		));
+		Ok(())
+	}
+
+	#[test]
+	fn public_paste_not_literal() -> sequoia_openpgp::Result<()> {
+		let cert = cert()?;
+
+		let mut compressed = Vec::new();
+		let message = Compressor::new(stream::Message::new(&mut compressed)).build()?;
+		finish(Signer::new(message, signer(&cert)?).build()?)?;
+		assert!(matches!(
+			validate_paste(&compressed, Visibility::Public, &cert),
+			Err(UserServerError::PublicPasteNotLiteral)
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn paste_signer_mismatch() -> sequoia_openpgp::Result<()> {
+		let (cert, other) = (cert()?, cert()?);
+
+		let mut public = Vec::new();
+		finish(Signer::new(stream::Message::new(&mut public), signer(&other)?).build()?)?;
+		assert!(matches!(
+			validate_paste(&public, Visibility::Public, &cert),
+			Err(UserServerError::PasteSignerMismatch)
+		));
+
+		let mut private = Vec::new();
+		let message = Signer::new(stream::Message::new(&mut private), signer(&other)?).build()?;
+		finish(Encryptor2::for_recipients(message, recipients(&cert)).build()?)?;
+		assert!(matches!(
+			validate_paste(&private, Visibility::Private, &cert),
+			Err(UserServerError::PasteSignerMismatch)
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn private_paste_without_recipients() -> sequoia_openpgp::Result<()> {
+		let mut protected = Vec::new();
+		finish(
+			Encryptor2::with_passwords(stream::Message::new(&mut protected), [PASSWORD]).build()?,
+		)?;
+		assert!(matches!(
+			validate_paste(&protected, Visibility::Private, &cert()?),
+			Err(UserServerError::PrivatePasteWithoutRecipients)
+		));
+		Ok(())
+	}
+
+	#[test]
+	fn private_paste_with_password() -> sequoia_openpgp::Result<()> {
+		let cert = cert()?;
+
+		let mut both = Vec::new();
+		let message =
+			Encryptor2::for_recipients(stream::Message::new(&mut both), recipients(&cert))
+				.add_passwords([PASSWORD])
+				.build()?;
+		finish(message)?;
+		assert!(matches!(
+			validate_paste(&both, Visibility::Private, &cert),
+			Err(UserServerError::PrivatePasteWithPassword)
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn protected_paste_without_password() -> sequoia_openpgp::Result<()> {
+		let cert = cert()?;
+
+		let mut private = Vec::new();
+		finish(
+			Encryptor2::for_recipients(stream::Message::new(&mut private), recipients(&cert))
+				.build()?,
+		)?;
+		assert!(matches!(
+			validate_paste(&private, Visibility::Protected, &cert),
+			Err(UserServerError::ProtectedPasteWithoutPassword)
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn protected_paste_with_recipients() -> sequoia_openpgp::Result<()> {
+		let cert = cert()?;
+
+		let mut both = Vec::new();
+		let message = Encryptor2::with_passwords(stream::Message::new(&mut both), [PASSWORD])
+			.add_recipients(recipients(&cert))
+			.build()?;
+		finish(message)?;
+		assert!(matches!(
+			validate_paste(&both, Visibility::Protected, &cert),
+			Err(UserServerError::ProtectedPasteWithRecipients)
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn plaintext_outside_encryption() -> sequoia_openpgp::Result<()> {
+		let cert = cert()?;
+
+		let mut literal = Vec::new();
+		finish(stream::Message::new(&mut literal))?;
+		assert!(matches!(
+			validate_paste(&literal, Visibility::Private, &cert),
+			Err(UserServerError::PlaintextOutsideEncryption)
+		));
+
+		let mut public = Vec::new();
+		finish(Signer::new(stream::Message::new(&mut public), signer(&cert)?).build()?)?;
+		assert!(matches!(
+			validate_paste(&public, Visibility::Protected, &cert),
+			Err(UserServerError::PlaintextOutsideEncryption)
+		));
+
 		Ok(())
 	}
 }
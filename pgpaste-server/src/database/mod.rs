@@ -1,6 +1,10 @@
 //! Models and triggers related to database management
 
-use diesel::{Connection, PgConnection};
+use diesel::{
+	Connection, PgConnection, RunQueryDsl,
+	dsl::{exists, not},
+	prelude::{ExpressionMethods, QueryDsl},
+};
 use diesel_async::{
 	AsyncPgConnection,
 	pooled_connection::deadpool::{Object, Pool},
@@ -9,7 +13,14 @@ use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
 use eyre::eyre;
 use secrecy::ExposeSecret;
 
-use crate::Config;
+use crate::{
+	Config,
+	crypto::signing_subkeys,
+	database::{
+		models::{Certificate, NewPublicKeySubkey},
+		schema::{public_key_subkeys, public_keys},
+	},
+};
 
 pub(crate) mod models;
 pub(crate) mod query;
@@ -38,6 +49,35 @@ pub(crate) fn run_migrations(config: &Config) -> eyre::Result<()> {
 
 	tracing::debug!(migrations = ?migrations_applied, "Applied migrations");
 
+	index_missing_subkeys(&mut connection)
+}
+
+/// Indexes the signing subkeys of the certs registered before subkeys were indexed
+fn index_missing_subkeys(connection: &mut PgConnection) -> eyre::Result<()> {
+	let unindexed = public_keys::table
+		.filter(not(exists(
+			public_key_subkeys::table.filter(public_key_subkeys::public_key_id.eq(public_keys::id)),
+		)))
+		.select((public_keys::id, public_keys::cert))
+		.load::<(i32, Certificate)>(connection)?;
+
+	for (public_key_id, cert) in unindexed {
+		let fingerprints = signing_subkeys(&cert.into());
+		let subkeys = fingerprints
+			.iter()
+			.map(|fingerprint| NewPublicKeySubkey {
+				fingerprint: fingerprint.as_bytes(),
+				public_key_id,
+			})
+			.collect::<Vec<_>>();
+
+		if !subkeys.is_empty() {
+			NewPublicKeySubkey::insert_all(&subkeys)
+				.on_conflict_do_nothing()
+				.execute(connection)?;
+		}
+	}
+
 	Ok(())
 }
 
@@ -14,7 +14,9 @@ use diesel::{
 };
 use sequoia_openpgp::{parse::Parse, serialize::MarshalInto};
 
-use super::schema::{self, pastes, public_keys};
+use super::schema::{
+	self, paste_recipients, paste_revisions, pastes, public_key_subkeys, public_keys,
+};
 
 #[derive(Debug, PartialEq, Eq, FromSqlRow, AsExpression)]
 #[diesel(sql_type = schema::sql_types::Visibility)]
@@ -144,12 +146,21 @@ pub(crate) struct NewPublicKey<'a> {
 	pub(crate) is_premium: bool,
 }
 
+/// Use to index a signing subkey of a [`PublicKey`]
+#[derive(Debug, Insertable)]
+#[diesel(table_name = public_key_subkeys)]
+pub(crate) struct NewPublicKeySubkey<'a> {
+	pub(crate) fingerprint: &'a [u8],
+	pub(crate) public_key_id: i32,
+}
+
 /// Represent a single signed or encrypted paste
 #[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable)]
 #[diesel(table_name = pastes)]
 pub(crate) struct Paste<'a> {
 	pub(crate) id: i32,
 	pub(crate) public_key_id: i32,
+	pub(crate) parent_id: Option<i32>,
 
 	pub(crate) slug: String,
 	pub(crate) mime: Mime<'a>,
@@ -158,13 +169,15 @@ pub(crate) struct Paste<'a> {
 
 	pub(crate) burn_at: SystemTime,
 	pub(crate) created_at: SystemTime,
+	pub(crate) burn_after_read: bool,
 }
 
-/// Use to create a new [`Paste`]
+/// Use to create a new [`Paste`], or to overwrite one where a missing parent is cleared
 #[derive(Debug, Insertable, AsChangeset)]
-#[diesel(table_name = pastes)]
+#[diesel(table_name = pastes, treat_none_as_null = true)]
 pub(crate) struct NewPaste<'a> {
 	pub(crate) public_key_id: i32,
+	pub(crate) parent_id: Option<i32>,
 
 	pub(crate) slug: &'a str,
 	pub(crate) mime: Mime<'a>,
@@ -175,3 +188,40 @@ pub(crate) struct NewPaste<'a> {
 	pub(crate) burn_at: &'a SystemTime,
 	pub(crate) burn_after_read: bool,
 }
+
+/// Represent a previous version of an overwritten [`Paste`]
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable)]
+#[diesel(table_name = paste_revisions)]
+pub(crate) struct PasteRevision<'a> {
+	pub(crate) id: i32,
+	pub(crate) paste_id: i32,
+
+	pub(crate) revision: i32,
+	pub(crate) mime: Mime<'a>,
+	pub(crate) visibility: Visibility,
+	pub(crate) content: Vec<u8>,
+
+	pub(crate) created_at: SystemTime,
+}
+
+/// Use to create a new [`PasteRevision`]
+#[derive(Debug, Insertable)]
+#[diesel(table_name = paste_revisions)]
+pub(crate) struct NewPasteRevision<'a> {
+	pub(crate) paste_id: i32,
+
+	pub(crate) revision: i32,
+	pub(crate) mime: &'a Mime<'a>,
+	pub(crate) visibility: &'a Visibility,
+	pub(crate) content: &'a [u8],
+
+	pub(crate) created_at: &'a SystemTime,
+}
+
+/// Use to index a recipient of a private [`Paste`]
+#[derive(Debug, Insertable)]
+#[diesel(table_name = paste_recipients)]
+pub(crate) struct NewPasteRecipient<'a> {
+	pub(crate) paste_id: i32,
+	pub(crate) key_id: &'a [u8],
+}
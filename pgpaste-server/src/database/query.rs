@@ -3,20 +3,31 @@
 
 //! Small bits of `Diesel` queries to reuse across the project
 
+use diesel::QueryResult;
 use diesel::{
 	dsl::{insert_into, now},
-	helper_types::{Eq, Filter, Gt, LtEq},
+	helper_types::{Eq, Filter, Gt, InnerJoin, LtEq},
 	query_builder::InsertStatement,
 };
+use diesel_async::AsyncPgConnection;
 use sequoia_openpgp::Fingerprint;
 
 use super::{
-	models::{NewPaste, NewPublicKey, Paste, PublicKey},
+	models::{
+		NewPaste, NewPasteRevision, NewPublicKey, NewPublicKeySubkey, Paste, PasteRevision,
+		PublicKey,
+	},
 	prelude::*,
-	schema::{pastes, public_keys},
+	schema::{paste_revisions, pastes, public_key_subkeys, public_keys},
 };
 
 impl PublicKey<'_> {
+	/// Select a public key from his `id`
+	#[inline]
+	pub(crate) fn with_id(id: i32) -> Filter<public_keys::table, Eq<public_keys::id, i32>> {
+		public_keys::table.filter(public_keys::id.eq(id))
+	}
+
 	/// Select a public key from his `fingerprint`
 	#[inline]
 	pub(crate) fn with_fingerprint(
@@ -24,6 +35,19 @@ impl PublicKey<'_> {
 	) -> Filter<public_keys::table, Eq<public_keys::fingerprint, &[u8]>> {
 		public_keys::table.filter(public_keys::fingerprint.eq(fingerprint.as_bytes()))
 	}
+
+	/// Select a public key from the `fingerprint` of one of its indexed signing subkeys
+	#[inline]
+	pub(crate) fn with_subkey(
+		fingerprint: &Fingerprint,
+	) -> Filter<
+		InnerJoin<public_keys::table, public_key_subkeys::table>,
+		Eq<public_key_subkeys::fingerprint, &[u8]>,
+	> {
+		public_keys::table
+			.inner_join(public_key_subkeys::table)
+			.filter(public_key_subkeys::fingerprint.eq(fingerprint.as_bytes()))
+	}
 }
 
 impl<'a> NewPublicKey<'a> {
@@ -39,6 +63,19 @@ impl<'a> NewPublicKey<'a> {
 	}
 }
 
+impl<'a> NewPublicKeySubkey<'a> {
+	/// Prepare an insert of several [`NewPublicKeySubkey`]
+	#[inline]
+	pub(crate) fn insert_all(
+		subkeys: &'a [Self],
+	) -> InsertStatement<
+		public_key_subkeys::table,
+		<&'a [NewPublicKeySubkey<'a>] as Insertable<public_key_subkeys::table>>::Values,
+	> {
+		insert_into(public_key_subkeys::table).values(subkeys)
+	}
+}
+
 impl Paste<'_> {
 	/// Select a paste from his `slug`
 	#[inline]
@@ -60,7 +97,32 @@ impl Paste<'_> {
 		Self::all_valid().filter(pastes::slug.eq(slug))
 	}
 
+	/// Select a paste from his `slug`, even if it is already burnt
+	#[inline]
+	pub(crate) fn with_slug_including_burnt(
+		slug: &str,
+	) -> Filter<pastes::table, Eq<pastes::slug, &str>> {
+		pastes::table.filter(pastes::slug.eq(slug))
+	}
+
+	/// Select a paste from his `id`
+	#[inline]
+	pub(crate) fn with_id(
+		id: i32,
+	) -> Filter<Filter<pastes::table, Gt<pastes::burn_at, now>>, Eq<pastes::id, i32>> {
+		Self::all_valid().filter(pastes::id.eq(id))
+	}
+
+	/// Select the pastes that reply to the paste with the given `id`
+	#[inline]
+	pub(crate) fn children_of(
+		id: i32,
+	) -> Filter<Filter<pastes::table, Gt<pastes::burn_at, now>>, Eq<pastes::parent_id, i32>> {
+		Self::all_valid().filter(pastes::parent_id.eq(id))
+	}
+
 	/// Return the number of pastes associated with this public key
+	#[allow(dead_code)]
 	#[inline]
 	pub(crate) fn all_of_public_key(
 		public_key_id: i32,
@@ -69,6 +131,31 @@ impl Paste<'_> {
 	}
 }
 
+impl Paste<'_> {
+	/// Load the slugs of the parent and of the children of this paste
+	pub(crate) async fn thread(
+		&self,
+		conn: &mut AsyncPgConnection,
+	) -> QueryResult<(Option<String>, Vec<String>)> {
+		let parent = match self.parent_id {
+			Some(parent_id) => Self::with_id(parent_id)
+				.select(pastes::slug)
+				.first::<String>(conn)
+				.await
+				.optional()?,
+			None => None,
+		};
+
+		let children = Self::children_of(self.id)
+			.select(pastes::slug)
+			.order(pastes::created_at.asc())
+			.load::<String>(conn)
+			.await?;
+
+		Ok((parent, children))
+	}
+}
+
 impl<'a> NewPaste<'a> {
 	/// Prepare a [`NewPaste`] insert
 	#[inline]
@@ -78,3 +165,38 @@ impl<'a> NewPaste<'a> {
 		insert_into(pastes::table).values(self)
 	}
 }
+
+impl PasteRevision<'_> {
+	/// Select all the revisions of a paste from its `id`
+	#[inline]
+	pub(crate) fn of_paste(
+		paste_id: i32,
+	) -> Filter<paste_revisions::table, Eq<paste_revisions::paste_id, i32>> {
+		paste_revisions::table.filter(paste_revisions::paste_id.eq(paste_id))
+	}
+
+	/// Select a single revision of a paste from its `id` and revision number
+	#[inline]
+	pub(crate) fn with_revision(
+		paste_id: i32,
+		revision: i32,
+	) -> Filter<
+		Filter<paste_revisions::table, Eq<paste_revisions::paste_id, i32>>,
+		Eq<paste_revisions::revision, i32>,
+	> {
+		Self::of_paste(paste_id).filter(paste_revisions::revision.eq(revision))
+	}
+}
+
+impl<'a> NewPasteRevision<'a> {
+	/// Prepare a [`NewPasteRevision`] insert
+	#[inline]
+	pub(crate) fn insert(
+		&'a self,
+	) -> InsertStatement<
+		paste_revisions::table,
+		<&'a NewPasteRevision<'a> as Insertable<paste_revisions::table>>::Values,
+	> {
+		insert_into(paste_revisions::table).values(self)
+	}
+}
@@ -1,11 +1,33 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
-    #[derive(diesel::sql_types::SqlType)]
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "visibility"))]
     pub struct Visibility;
 }
 
+diesel::table! {
+    paste_recipients (paste_id, key_id) {
+        paste_id -> Int4,
+        key_id -> Bytea,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Visibility;
+
+    paste_revisions (id) {
+        id -> Int4,
+        paste_id -> Int4,
+        revision -> Int4,
+        mime -> Text,
+        visibility -> Visibility,
+        content -> Bytea,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::Visibility;
@@ -21,6 +43,14 @@ diesel::table! {
         created_at -> Timestamp,
         burn_at -> Timestamp,
         burn_after_read -> Bool,
+        parent_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    public_key_subkeys (fingerprint) {
+        fingerprint -> Bytea,
+        public_key_id -> Int4,
     }
 }
 
@@ -33,9 +63,15 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(paste_recipients -> pastes (paste_id));
+diesel::joinable!(paste_revisions -> pastes (paste_id));
 diesel::joinable!(pastes -> public_keys (public_key_id));
+diesel::joinable!(public_key_subkeys -> public_keys (public_key_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    paste_recipients,
+    paste_revisions,
     pastes,
+    public_key_subkeys,
     public_keys,
 );
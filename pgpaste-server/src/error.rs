@@ -16,6 +16,9 @@ pub(crate) enum ServerError {
 	/// Database pool connection error
 	#[error("DbPool error: {0}")]
 	Pool(#[from] deadpool::PoolError),
+	/// Database query error, mostly used to fail transactions
+	#[error("Database error: {0}")]
+	Database(#[from] diesel::result::Error),
 
 	/// Provide additional informations to the user
 	#[error(transparent)]
@@ -27,7 +30,9 @@ impl IntoResponse for ServerError {
 		tracing::error!(error = ?self);
 
 		match self {
-			Self::Eyre(_) | Self::Pool(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+			Self::Eyre(_) | Self::Pool(_) | Self::Database(_) => {
+				StatusCode::INTERNAL_SERVER_ERROR.into_response()
+			}
 			Self::User(error) => error.into_response(),
 		}
 	}
@@ -43,6 +48,33 @@ pub(crate) enum UserServerError {
 	/// Invalid message structure
 	#[error("Invalid message structure")]
 	InvalidMessageStructure,
+	/// The message of a paste is not an `OpenPGP` message
+	#[error("Invalid paste message")]
+	InvalidPasteMessage(eyre::Error),
+	/// Public paste content is not signed
+	#[error("Public pastes must be signed")]
+	PublicPasteNotSigned,
+	/// Public paste content is encrypted, compressed or has unexpected packets
+	#[error("Public pastes must be a signed literal, without encryption or compression")]
+	PublicPasteNotLiteral,
+	/// Paste content is signed by another key than the request
+	#[error("Pastes must be signed by the key that signed the request")]
+	PasteSignerMismatch,
+	/// Private paste is not encrypted to any key
+	#[error("Private pastes must be encrypted to at least one key")]
+	PrivatePasteWithoutRecipients,
+	/// Private paste can also be decrypted with a password
+	#[error("Private pastes must not be encrypted with a password")]
+	PrivatePasteWithPassword,
+	/// Protected paste is not encrypted with a password
+	#[error("Protected pastes must be encrypted with a password")]
+	ProtectedPasteWithoutPassword,
+	/// Protected paste can also be decrypted with a key
+	#[error("Protected pastes must not be encrypted to keys")]
+	ProtectedPasteWithRecipients,
+	/// Private or protected paste has packets outside of the encryption
+	#[error("Private and protected pastes must not have data outside of the encryption")]
+	PlaintextOutsideEncryption,
 	/// Invalid signature
 	#[error("Invalid signature")]
 	InvalidSignature(eyre::Error),
@@ -51,6 +83,10 @@ pub(crate) enum UserServerError {
 	#[error("Certificate is not known within the default keyserver `keys.openpgp.org`")]
 	CertUnknown(eyre::Error),
 
+	/// Signed request was issued too long ago or too far in the future
+	#[error("Signed request is expired, check your clock")]
+	StaleRequest,
+
 	/// TODO
 	#[error("Certificate is not known within the default keyserver `keys.openpgp.org`")]
 	MsgPackBodyIsInvalid(#[from] rmp_serde::decode::Error),
@@ -58,10 +94,30 @@ pub(crate) enum UserServerError {
 	/// Queried paste not found
 	#[error("Paste not found")]
 	PasteNotFound,
+	/// Queried paste revision not found
+	#[error("Paste revision not found")]
+	RevisionNotFound,
+	/// A paste with the same slug already exists
+	#[error("A paste with the same slug already exists")]
+	PasteAlreadyExists,
+	/// Parent paste does not exist or is the paste itself
+	#[error("Parent paste does not exist or is the paste itself")]
+	InvalidParent,
+	/// Paste belongs to another key and cannot be overwritten
+	#[error("Paste belongs to another key and cannot be overwritten")]
+	PasteNotOwned,
 	/// Burn date is too far in the future
 	#[error("Burn date is too far in the future")]
 	InvalidBurnIn,
 
+	/// Burn date is already past
+	#[error("Burn date is in the past")]
+	BurnDateInPast,
+
+	/// Both a lifetime and a burn date were given
+	#[error("Only one of a lifetime or a burn date can be given")]
+	AmbiguousBurnDate,
+
 	/// Paste is private and cannot be accessed like this
 	#[error("Paste is private and cannot be accessed like this")]
 	PasteIsPrivate,
@@ -76,14 +132,29 @@ impl IntoResponse for UserServerError {
 		let code = match self {
 			Self::InvalidCert(_)
 			| Self::InvalidMessageStructure
+			| Self::InvalidPasteMessage(_)
+			| Self::PublicPasteNotSigned
+			| Self::PublicPasteNotLiteral
+			| Self::PasteSignerMismatch
+			| Self::PrivatePasteWithoutRecipients
+			| Self::PrivatePasteWithPassword
+			| Self::ProtectedPasteWithoutPassword
+			| Self::ProtectedPasteWithRecipients
+			| Self::PlaintextOutsideEncryption
 			| Self::InvalidBurnIn
+			| Self::BurnDateInPast
+			| Self::AmbiguousBurnDate
+			| Self::InvalidParent
+			| Self::StaleRequest
 			| Self::InvalidSignature(_)
 			| Self::CertUnknown(_)
 			| Self::MsgPackBodyIsInvalid(_)
 			| Self::PasteIsPrivate
 			| Self::PasteIsProtected => StatusCode::BAD_REQUEST,
 
-			Self::PasteNotFound => StatusCode::NOT_FOUND,
+			Self::PasteNotFound | Self::RevisionNotFound => StatusCode::NOT_FOUND,
+			Self::PasteAlreadyExists => StatusCode::CONFLICT,
+			Self::PasteNotOwned => StatusCode::FORBIDDEN,
 		};
 
 		(code, format!("{self}")).into_response()
@@ -3,11 +3,13 @@
 use axum::{
 	Router,
 	extract::{Path, State},
-	http::StatusCode,
-	response::IntoResponse,
+	http::{HeaderMap, StatusCode, header},
+	response::{Html, IntoResponse, Response},
 	routing::get,
 };
 use eyre::{ContextCompat, WrapErr};
+use mime::Mime;
+use pgpaste_api_types::bundle::{self, BundleError};
 use sequoia_openpgp::{Message, parse::Parse};
 
 use crate::{
@@ -22,14 +24,17 @@ use crate::{
 
 /// The API routes definition
 pub(crate) fn pastes_router() -> Router<AppState> {
-	Router::new().route("/:paste_slug", get(get_public_paste))
+	Router::new().route("/{paste_slug}", get(get_public_paste))
 }
 
-#[tracing::instrument(skip(state))]
+/// Serves the raw content of a public paste, or an HTML page linking its thread to
+/// clients that prefer HTML like browsers
+#[tracing::instrument(skip(state, headers))]
 pub(crate) async fn get_public_paste<'a>(
 	State(state): State<AppState>,
 	Path(paste_slug): Path<String>,
-) -> Result<impl IntoResponse, ServerError> {
+	headers: HeaderMap,
+) -> Result<Response, ServerError> {
 	let mut conn = state.database.get().await?;
 
 	let Some(paste) = Paste::with_slug(&paste_slug)
@@ -57,9 +62,141 @@ pub(crate) async fn get_public_paste<'a>(
 		}
 	};
 
+	if !accepts_html(&headers) {
+		return Ok((
+			StatusCode::OK,
+			[("Content-Type", "text/plain")],
+			message.to_owned(),
+		)
+			.into_response());
+	}
+
+	let (parent, children) = paste
+		.thread(&mut conn)
+		.await
+		.wrap_err("Failed to load paste thread")?;
+
+	let content = if bundle::is_bundle(&paste.mime.0) {
+		render_bundle(message).wrap_err("internal state error, paste has an invalid bundle")?
+	} else {
+		render_file(&paste.mime.0, message)
+	};
+
 	Ok((
 		StatusCode::OK,
-		[("Content-Type", "text/plain")],
-		message.to_owned(),
-	))
+		Html(render_paste(
+			&paste.slug,
+			&content,
+			parent.as_deref(),
+			&children,
+		)),
+	)
+		.into_response())
+}
+
+/// Whether the `Accept` header of the request lists HTML
+fn accepts_html(headers: &HeaderMap) -> bool {
+	headers
+		.get_all(header::ACCEPT)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.any(|range| {
+			range
+				.split(';')
+				.next()
+				.is_some_and(|essence| essence.trim() == "text/html")
+		})
+}
+
+/// Render the list of files of a bundle and the content of the textual ones
+fn render_bundle(data: &[u8]) -> Result<String, BundleError> {
+	let (manifest, contents) = bundle::decode(data)?;
+
+	let mut list = String::from("<ul>");
+	let mut files = String::new();
+
+	for (index, (entry, content)) in manifest.entries.iter().zip(contents).enumerate() {
+		let name = escape_html(&entry.name);
+		let mime = escape_html(entry.mime.as_ref());
+
+		list.push_str(&format!(
+			"<li><a href=\"#file-{index}\">{name}</a> ({mime}, {} bytes)</li>",
+			entry.size
+		));
+
+		let body = render_file(&entry.mime, content);
+		files.push_str(&format!("<h2 id=\"file-{index}\">{name}</h2>{body}"));
+	}
+
+	list.push_str("</ul>");
+
+	Ok(list + &files)
+}
+
+/// Render a textual content with a language hint for highlighters, binary files are not displayed
+fn render_file(mime: &Mime, content: &[u8]) -> String {
+	if mime.type_() != mime::TEXT && mime.subtype() != mime::JSON {
+		return String::from("<p>Binary file not displayed</p>");
+	}
+
+	// `text/x-rust` becomes `language-rust`
+	let language = mime
+		.subtype()
+		.as_str()
+		.strip_prefix("x-")
+		.unwrap_or(mime.subtype().as_str());
+
+	format!(
+		"<pre><code class=\"language-{}\">{}</code></pre>",
+		escape_html(language),
+		escape_html(&String::from_utf8_lossy(content))
+	)
+}
+
+/// Render a public paste and the links to its thread as an HTML page
+///
+/// `content` must already be escaped
+fn render_paste(slug: &str, content: &str, parent: Option<&str>, children: &[String]) -> String {
+	let mut thread = String::new();
+
+	if let Some(parent) = parent {
+		let parent = escape_html(parent);
+		thread.push_str(&format!(
+			"<p>In reply to <a href=\"/p/{parent}\">{parent}</a></p>"
+		));
+	}
+
+	if !children.is_empty() {
+		thread.push_str("<p>Replies:</p><ul>");
+		for child in children {
+			let child = escape_html(child);
+			thread.push_str(&format!("<li><a href=\"/p/{child}\">{child}</a></li>"));
+		}
+		thread.push_str("</ul>");
+	}
+
+	format!(
+		"<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{slug}</title></head>\
+		 <body><nav>{thread}</nav>{content}</body></html>",
+		slug = escape_html(slug),
+	)
+}
+
+/// Escape the characters that have a meaning in HTML
+fn escape_html(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			c => escaped.push(c),
+		}
+	}
+
+	escaped
 }